@@ -1,10 +1,14 @@
 use bevy::{input::system::exit_on_esc_system, prelude::*};
 use bevy_prototype_character_controller::{
+    backend::ControllerMode,
+    camera::CameraBoom,
     controller::{
-        BodyTag, CameraTag, CharacterController, CharacterControllerPlugin, HeadTag, Mass, YawTag,
+        BodyTag, CameraTag, CharacterController, CharacterControllerPlugin, HeadTag, YawTag,
     },
-    events::TranslationEvent,
-    look::{LookDirection, LookEntity},
+    interpolation::{RenderInterpolation, RenderInterpolationPlugin},
+    look::{LookDirection, LookEntity, MouseLookTag},
+    preset::{ControllerPreset, ControllerPresetPlugin},
+    simple::{AabbCollider, CapsuleCollider, SimpleBackend},
 };
 use rand::Rng;
 
@@ -28,13 +32,15 @@ impl Default for CharacterSettings {
     }
 }
 
-pub struct FakeKinematicRigidBody;
-
 pub fn build_app(app: &mut AppBuilder) {
     app.insert_resource(ClearColor(Color::hex("101010").unwrap()))
         .insert_resource(Msaa { samples: 4 })
         .add_plugins(DefaultPlugins)
-        .add_plugin(CharacterControllerPlugin)
+        .add_plugin(CharacterControllerPlugin::<SimpleBackend>::new(
+            ControllerMode::KinematicTranslation,
+        ))
+        .add_plugin(ControllerPresetPlugin)
+        .add_plugin(RenderInterpolationPlugin)
         .add_system(exit_on_esc_system.system())
         .add_startup_system(spawn_world.system())
         .add_startup_system(spawn_character.system());
@@ -55,16 +61,21 @@ pub fn spawn_world(
 
     // Ground cuboid
     let grey = materials.add(Color::hex("808080").unwrap().into());
-    commands.spawn_bundle(PbrBundle {
-        material: grey,
-        mesh: cube.clone(),
-        transform: Transform::from_matrix(Mat4::from_scale_rotation_translation(
-            Vec3::new(20.0, 1.0, 20.0),
-            Quat::IDENTITY,
-            -Vec3::Y,
-        )),
-        ..Default::default()
-    });
+    let ground_scale = Vec3::new(20.0, 1.0, 20.0);
+    commands
+        .spawn_bundle(PbrBundle {
+            material: grey,
+            mesh: cube.clone(),
+            transform: Transform::from_matrix(Mat4::from_scale_rotation_translation(
+                ground_scale,
+                Quat::IDENTITY,
+                -Vec3::Y,
+            )),
+            ..Default::default()
+        })
+        .insert(AabbCollider {
+            half_extents: 0.5 * ground_scale,
+        });
 
     // Cubes for some kind of reference in the scene to make it easy to see
     // what is happening
@@ -74,36 +85,55 @@ pub fn spawn_world(
     for _ in 0..20 {
         let x = rng.gen_range(-10.0..10.0);
         let z = rng.gen_range(-10.0..10.0);
-        commands.spawn_bundle(PbrBundle {
-            material: teal.clone(),
-            mesh: cube.clone(),
-            transform: Transform::from_matrix(Mat4::from_scale_rotation_translation(
-                Vec3::splat(cube_scale),
-                Quat::IDENTITY,
-                Vec3::new(x, 0.5 * (cube_scale - 1.0), z),
-            )),
-            ..Default::default()
-        });
+        commands
+            .spawn_bundle(PbrBundle {
+                material: teal.clone(),
+                mesh: cube.clone(),
+                transform: Transform::from_matrix(Mat4::from_scale_rotation_translation(
+                    Vec3::splat(cube_scale),
+                    Quat::IDENTITY,
+                    Vec3::new(x, 0.5 * (cube_scale - 1.0), z),
+                )),
+                ..Default::default()
+            })
+            .insert(AabbCollider {
+                half_extents: Vec3::splat(0.5 * cube_scale),
+            });
     }
 }
 
 pub fn spawn_character(
     mut commands: Commands,
+    asset_server: Res<AssetServer>,
     character_settings: Res<CharacterSettings>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut meshes: ResMut<Assets<Mesh>>,
 ) {
+    // Edit the preset while the example is running to retune the character
+    asset_server
+        .watch_for_changes()
+        .expect("Failed to watch for asset changes");
+    let preset: Handle<ControllerPreset> = asset_server.load("presets/character.controller.ron");
+
     let cube = meshes.add(Mesh::from(shape::Cube { size: 1.0 }));
     let red = materials.add(Color::hex("800000").unwrap().into());
 
+    // The body origin is 0.5 above the feet, standing on the ground cuboid
+    let half_total_height = 0.5 * character_settings.scale.y;
+    let radius = 0.5 * character_settings.scale.x.max(character_settings.scale.z);
     let body = commands
         .spawn_bundle((
             GlobalTransform::identity(),
             Transform::identity(),
             CharacterController::default(),
-            FakeKinematicRigidBody,
-            Mass::new(80.0),
+            CapsuleCollider {
+                radius,
+                half_height: half_total_height - radius,
+                center: (half_total_height - 0.5) * Vec3::Y,
+                up: Vec3::Y,
+            },
             BodyTag,
+            RenderInterpolation,
         ))
         .id();
     let yaw = commands
@@ -151,31 +181,19 @@ pub fn spawn_character(
         })
         .insert_bundle((LookDirection::default(), CameraTag))
         .id();
+    // Keep third-person cameras out of the obstacles behind the character
+    if character_settings.follow_offset != Vec3::ZERO {
+        commands.entity(camera).insert(CameraBoom::new(
+            character_settings
+                .follow_offset
+                .distance(character_settings.focal_point),
+            character_settings.focal_point,
+        ));
+    }
     commands
         .entity(body)
-        .insert(LookEntity(camera))
+        .insert_bundle((LookEntity(camera), MouseLookTag, preset))
         .push_children(&[yaw]);
     commands.entity(yaw).push_children(&[body_model, head]);
     commands.entity(head).push_children(&[head_model, camera]);
 }
-
-pub fn controller_to_kinematic(
-    mut translations: EventReader<TranslationEvent>,
-    mut query: Query<
-        (&mut Transform, &mut CharacterController),
-        (With<BodyTag>, With<FakeKinematicRigidBody>),
-    >,
-) {
-    for (mut transform, mut controller) in query.iter_mut() {
-        for translation in translations.iter() {
-            transform.translation += **translation;
-        }
-        // NOTE: This is just an example to stop falling past the initial body height
-        // With a physics engine you would indicate that the body has collided with
-        // something and should stop, depending on how your game works.
-        if transform.translation.y < 0.0 {
-            transform.translation.y = 0.0;
-            controller.jumping = false;
-        }
-    }
-}
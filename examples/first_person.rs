@@ -4,7 +4,7 @@ use bevy_prototype_character_controller::controller::{controller_to_pitch, contr
 // Take a look at example_utils/utils.rs for details!
 #[path = "../example_utils/utils.rs"]
 mod utils;
-use utils::{build_app, controller_to_kinematic, CharacterSettings};
+use utils::{build_app, CharacterSettings};
 
 fn main() {
     let mut app = App::build();
@@ -14,7 +14,6 @@ fn main() {
         follow_offset: Vec3::ZERO, // Relative to head
         ..Default::default()
     })
-    .add_system(controller_to_kinematic.system())
     .add_system(controller_to_yaw.system())
     .add_system(controller_to_pitch.system())
     .run();
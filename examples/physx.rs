@@ -1,10 +1,7 @@
-use bevy::{app::Events, input::system::exit_on_esc_system, prelude::*};
+use bevy::{input::system::exit_on_esc_system, prelude::*};
 use bevy_prototype_character_controller::{
-    controller::{
-        BodyTag, CameraTag, CharacterController, CharacterControllerPlugin, HeadTag, Mass, YawTag,
-    },
-    events::TranslationEvent,
-    look::{LookDirection, LookEntity},
+    controller::{BodyTag, CameraTag, CharacterController, HeadTag, Mass, YawTag},
+    look::{LookDirection, LookEntity, MouseLookTag},
     physx::*,
 };
 use bevy_prototype_physx::*;
@@ -50,16 +47,13 @@ fn main() {
         .insert_resource(Msaa { samples: 4 })
         .add_plugins(DefaultPlugins)
         .add_system(exit_on_esc_system.system())
-        // Character Controller
-        .add_plugin(CharacterControllerPlugin)
         // PhysX
         .add_plugin(PhysXPlugin);
     // Character controller adaptations for PhysX
     println!("Using {:?} method", controller_type);
     if controller_type == ControllerType::KinematicTranslation {
         // Option A. Apply translations (changes in position)
-        app.add_plugin(PhysXKinematicTranslationCharacterControllerPlugin)
-            .add_system_to_stage(CoreStage::Update, controller_to_physx_kinematic.system());
+        app.add_plugin(PhysXKinematicTranslationCharacterControllerPlugin);
     } else if controller_type == ControllerType::DynamicImpulse {
         // Option B. Apply impulses (changes in momentum)
         app.add_plugin(PhysXDynamicImpulseCharacterControllerPlugin);
@@ -287,41 +281,8 @@ fn spawn_body_children(
         .id();
     commands
         .entity(body)
-        .insert(LookEntity(camera))
+        .insert_bundle((LookEntity(camera), MouseLookTag))
         .push_children(&[yaw]);
     commands.entity(yaw).push_children(&[body_model, head]);
     commands.entity(head).push_children(&[head_model, camera]);
 }
-
-pub fn controller_to_physx_kinematic(
-    mut translations: EventReader<TranslationEvent>,
-    character_settings: Res<CharacterSettings>,
-    mut _physx: ResMut<PhysX>, // For synchronization
-    mut query: Query<
-        (
-            &mut PhysXController,
-            &mut Transform,
-            &mut CharacterController,
-        ),
-        With<BodyTag>,
-    >,
-) {
-    let mut translation = Vec3::ZERO;
-    for event in translations.iter() {
-        translation += **event;
-    }
-    // NOTE: This is just an example to stop falling past the initial body height
-    // With a physics engine you would indicate that the body has collided with
-    // something and should stop, depending on how your game works.
-    let min_y = 0.5 * (1.0 + character_settings.scale.y);
-    for (mut physx_controller, mut transform, mut controller) in query.iter_mut() {
-        let position = physx_controller.get_position();
-        if position.y + translation.y < min_y {
-            translation.y = min_y - position.y;
-            controller.jumping = false;
-        }
-        let new_position = position + translation;
-        physx_controller.set_position(new_position);
-        transform.translation += translation;
-    }
-}
@@ -3,7 +3,7 @@ use bevy::prelude::*;
 // Take a look at example_utils/utils.rs for details!
 #[path = "../example_utils/utils.rs"]
 mod utils;
-use utils::{build_app, controller_to_kinematic, CharacterSettings};
+use utils::{build_app, CharacterSettings};
 
 fn main() {
     let mut app = App::build();
@@ -14,6 +14,5 @@ fn main() {
         head_yaw: 0.5 * std::f32::consts::TAU,
         ..Default::default()
     })
-    .add_system(controller_to_kinematic.system())
     .run();
 }
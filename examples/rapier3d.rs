@@ -1,7 +1,7 @@
 use bevy::{input::system::exit_on_esc_system, prelude::*};
 use bevy_prototype_character_controller::{
     controller::{BodyTag, CameraTag, CharacterController, HeadTag, YawTag},
-    look::{LookDirection, LookEntity},
+    look::{LookDirection, LookEntity, MouseLookTag},
     rapier::*,
 };
 use bevy_rapier3d::{
@@ -23,6 +23,7 @@ use utils::*;
 arg_enum! {
     #[derive(PartialEq, Debug)]
     pub enum ControllerType {
+        KinematicTranslation,
         DynamicImpulse,
         DynamicForce,
     }
@@ -57,16 +58,20 @@ fn main() {
     // IMPORTANT: The impulse/force systems MUST run before the physics simulation step, so they
     // either need to be added to the end of PRE_UPDATE or the beginning of UPDATE
     println!("Using {:?} method", controller_type);
-    if controller_type == ControllerType::DynamicImpulse {
-        // Option A. Apply impulses (changes in momentum)
+    if controller_type == ControllerType::KinematicTranslation {
+        // Option A. Apply translations (changes in position)
+        app.add_plugin(RapierKinematicTranslationCharacterControllerPlugin);
+    } else if controller_type == ControllerType::DynamicImpulse {
+        // Option B. Apply impulses (changes in momentum)
         app.add_plugin(RapierDynamicImpulseCharacterControllerPlugin);
     } else {
-        // Option B. Apply forces (rate of change of momentum)
+        // Option C. Apply forces (rate of change of momentum)
         app.add_plugin(RapierDynamicForceCharacterControllerPlugin);
     }
 
     // Specific to this demo
     app.init_resource::<CharacterSettings>()
+        .insert_resource(controller_type)
         .add_startup_system(spawn_world.system())
         .add_startup_system(spawn_character.system())
         .run();
@@ -151,6 +156,7 @@ pub fn spawn_world(
 
 pub fn spawn_character(
     mut commands: Commands,
+    controller_type: Res<ControllerType>,
     character_settings: Res<CharacterSettings>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -170,7 +176,11 @@ pub fn spawn_character(
                 sleeping: false,
                 ..Default::default()
             },
-            body_type: RigidBodyType::Dynamic,
+            body_type: if *controller_type == ControllerType::KinematicTranslation {
+                RigidBodyType::KinematicPositionBased
+            } else {
+                RigidBodyType::Dynamic
+            },
             mass_properties: RigidBodyMassPropsFlags::ROTATION_LOCKED.into(),
             position: RigidBodyPosition {
                 position: (0.5 * (box_y + character_settings.scale.y) * Vec3::Y).into(),
@@ -246,7 +256,7 @@ pub fn spawn_character(
         .id();
     commands
         .entity(body)
-        .insert(LookEntity(camera))
+        .insert_bundle((LookEntity(camera), MouseLookTag))
         .push_children(&[yaw]);
     commands.entity(yaw).push_children(&[body_model, head]);
     commands.entity(head).push_children(&[head_model, camera]);
@@ -0,0 +1,758 @@
+/*
+ * Physics backend abstraction
+ *
+ * The character controller systems are written once against this trait and
+ * each physics engine integration only has to say how to read and drive a
+ * character body.
+ */
+
+use crate::{controller::*, events::*};
+use bevy::{
+    ecs::{
+        component::Component,
+        query::{Fetch, WorldQuery},
+    },
+    prelude::*,
+    utils::HashMap,
+};
+
+pub const BODY_TO_GROUND_STATE_SYSTEM: &str = "body_to_ground_state";
+pub const BODY_TO_VELOCITY_SYSTEM: &str = "body_to_velocity";
+pub const CONTROLLER_TO_CROUCH_SYSTEM: &str = "controller_to_crouch";
+pub const CONTROLLER_TO_GRAVITY_SYSTEM: &str = "controller_to_gravity";
+pub const CONTROLLER_TO_DYNAMIC_IMPULSE_SYSTEM: &str = "controller_to_dynamic_impulse";
+pub const CONTROLLER_TO_DYNAMIC_FORCE_SYSTEM: &str = "controller_to_dynamic_force";
+pub const CONTROLLER_TO_KINEMATIC_TRANSLATION_SYSTEM: &str = "controller_to_kinematic_translation";
+pub const CONTROLLER_TO_STEP_SYSTEM: &str = "controller_to_step";
+pub const CREATE_GROUND_STATE_SYSTEM: &str = "create_ground_state";
+pub const CREATE_MASS_SYSTEM: &str = "create_mass";
+
+/// How the character body is driven by the controller events
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControllerMode {
+    /// Apply `TranslationEvent`s to a kinematic body
+    KinematicTranslation,
+    /// Apply `ImpulseEvent`s to a dynamic body
+    DynamicImpulse,
+    /// Apply `ForceEvent`s to a dynamic body
+    DynamicForce,
+}
+
+/// A contact found by a shape cast or ground query
+#[derive(Clone, Copy, Debug)]
+pub struct ShapeHit {
+    pub entity: Option<Entity>,
+    pub distance: f32,
+    pub point: Vec3,
+    pub normal: Vec3,
+}
+
+/// Colliders that a cast that does not belong to a body should ignore
+#[derive(Clone, Debug, Default)]
+pub struct CastFilter {
+    pub ignore_entities: Vec<Entity>,
+    /// Collision groups, as a bit mask, whose colliders are ignored. Only
+    /// supported by backends with collision groups.
+    pub ignore_groups: u32,
+}
+
+impl CastFilter {
+    pub fn ignores(&self, entity: Option<Entity>) -> bool {
+        entity.map_or(false, |entity| self.ignore_entities.contains(&entity))
+    }
+}
+
+/// The outcome of moving a kinematic body
+#[derive(Debug, Default)]
+pub struct KinematicMove {
+    pub translation: Vec3,
+    pub hits: Vec<ShapeHit>,
+    /// Height climbed up a step, or dropped down one if negative
+    pub step: f32,
+}
+
+/// How `set_crouched` changed a body
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CrouchResize {
+    /// Height actually removed from the collider, or added back to it
+    pub height: f32,
+    /// How far the body origin moved up with the resize, for backends that
+    /// keep the origin at the centre of the collider
+    pub shift: f32,
+}
+
+/// What a kinematic move needs to know about the character
+#[derive(Clone, Copy, Debug)]
+pub struct KinematicSettings {
+    /// Opposite to the character's gravity
+    pub up: Vec3,
+    /// Steepest ground, in radians, that can be walked on
+    pub max_slope_angle: f32,
+    /// Highest ledge that can be stepped up onto, and lowest that is stepped
+    /// down from without leaving the ground
+    pub max_step_height: f32,
+    /// Whether to stay on the ground when walking down steps and slopes
+    pub snap_to_ground: bool,
+}
+
+impl KinematicSettings {
+    /// Settings for `controller`, snapping to the ground if it was `grounded`
+    /// and is not jumping
+    pub fn new(controller: &CharacterController, up: Vec3, grounded: bool) -> Self {
+        Self {
+            up,
+            max_slope_angle: controller.max_slope_angle,
+            max_step_height: controller.max_step_height,
+            snap_to_ground: grounded && !controller.jumping && !controller.fly,
+        }
+    }
+
+    /// Whether ground with `normal` is shallow enough to walk on
+    pub fn walkable(&self, normal: Vec3) -> bool {
+        normal.dot(self.up) >= self.max_slope_angle.cos()
+    }
+}
+
+/// The query item of a backend's character body components
+pub type BodyItem<'w, B> =
+    <<<B as CharacterPhysicsBackend>::Body as WorldQuery>::Fetch as Fetch<'w>>::Item;
+
+/// The query item of a backend's platform components
+pub type PlatformItem<'w, B> =
+    <<<B as CharacterPhysicsBackend>::Platform as WorldQuery>::Fetch as Fetch<'w>>::Item;
+
+pub trait CharacterPhysicsBackend: Send + Sync + 'static {
+    /// Resource used to query and modify the physics scene
+    type Scene: Component;
+    /// Components of a character body
+    type Body: WorldQuery + 'static;
+    /// Components of the colliders that casts are tested against
+    type Colliders: WorldQuery + 'static;
+    /// Components of a body that characters can stand on and be carried by
+    type Platform: WorldQuery + 'static;
+
+    /// Add any systems that are specific to this backend
+    fn build(_app: &mut AppBuilder) {}
+
+    fn mass(scene: &Self::Scene, body: &BodyItem<'_, Self>) -> Option<f32>;
+
+    fn velocity(scene: &Self::Scene, body: &BodyItem<'_, Self>) -> Vec3;
+
+    fn apply_impulse(scene: &mut Self::Scene, body: &mut BodyItem<'_, Self>, impulse: Vec3);
+
+    fn apply_force(scene: &mut Self::Scene, body: &mut BodyItem<'_, Self>, force: Vec3);
+
+    /// Make a dynamic body fall with the character's own gravity instead of
+    /// that of the physics world
+    fn apply_gravity(
+        _scene: &mut Self::Scene,
+        _body: &mut BodyItem<'_, Self>,
+        _gravity: Vec3,
+        _mass: f32,
+    ) {
+    }
+
+    /// Move a kinematic body without climbing slopes that are too steep
+    fn move_kinematic(
+        scene: &mut Self::Scene,
+        colliders: &Query<Self::Colliders>,
+        entity: Entity,
+        body: &mut BodyItem<'_, Self>,
+        translation: Vec3,
+        settings: &KinematicSettings,
+    ) -> KinematicMove;
+
+    /// Move a kinematic body without checking for collisions, for noclip
+    fn translate_kinematic(
+        scene: &mut Self::Scene,
+        body: &mut BodyItem<'_, Self>,
+        translation: Vec3,
+    );
+
+    /// Linear velocity of a platform at `point` and its angular velocity
+    fn platform_velocity(
+        _scene: &Self::Scene,
+        _platform: &PlatformItem<'_, Self>,
+        _point: Vec3,
+    ) -> (Vec3, Vec3) {
+        (Vec3::ZERO, Vec3::ZERO)
+    }
+
+    /// Move a body by `translation` without checking for collisions, to step
+    /// up onto a ledge or down onto the ground below
+    fn teleport(scene: &mut Self::Scene, body: &mut BodyItem<'_, Self>, translation: Vec3) {
+        Self::translate_kinematic(scene, body, translation);
+    }
+
+    /// Sweep the body's shape from its position plus `offset` along `direction`
+    fn cast_shape(
+        scene: &Self::Scene,
+        colliders: &Query<Self::Colliders>,
+        entity: Entity,
+        body: &BodyItem<'_, Self>,
+        offset: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+    ) -> Option<ShapeHit>;
+
+    /// Sweep a sphere from `origin` along `direction`
+    fn cast_sphere(
+        scene: &Self::Scene,
+        colliders: &Query<Self::Colliders>,
+        origin: Vec3,
+        direction: Vec3,
+        radius: f32,
+        max_distance: f32,
+        filter: &CastFilter,
+    ) -> Option<ShapeHit>;
+
+    /// Shrink the body's collider by up to `offset` from the top when
+    /// crouching, or grow it back by `offset` when standing up
+    fn set_crouched(
+        _commands: &mut Commands,
+        _entity: Entity,
+        _body: &mut BodyItem<'_, Self>,
+        _crouched: bool,
+        offset: f32,
+    ) -> CrouchResize {
+        CrouchResize {
+            height: offset,
+            shift: 0.0,
+        }
+    }
+
+    fn query_ground(
+        scene: &Self::Scene,
+        colliders: &Query<Self::Colliders>,
+        entity: Entity,
+        body: &BodyItem<'_, Self>,
+        up: Vec3,
+        max_distance: f32,
+    ) -> Option<ShapeHit> {
+        Self::cast_shape(
+            scene,
+            colliders,
+            entity,
+            body,
+            Vec3::ZERO,
+            -up,
+            max_distance,
+        )
+    }
+}
+
+const SLIDE_ITERATIONS: usize = 4;
+const SKIN_WIDTH: f32 = 0.01;
+
+/// Move until the first contact, then project the remaining translation onto
+/// the contact plane and try again. Slopes that are too steep to walk on are
+/// treated as walls unless moving down them. Backends without their own
+/// character controller can use this to implement `move_kinematic`.
+pub fn collide_and_slide<B: CharacterPhysicsBackend>(
+    scene: &B::Scene,
+    colliders: &Query<B::Colliders>,
+    entity: Entity,
+    body: &BodyItem<'_, B>,
+    translation: Vec3,
+    settings: &KinematicSettings,
+) -> KinematicMove {
+    slide_from::<B>(
+        scene,
+        colliders,
+        entity,
+        body,
+        Vec3::ZERO,
+        translation,
+        settings,
+    )
+}
+
+/// `collide_and_slide` starting from the body's position plus `offset`
+fn slide_from<B: CharacterPhysicsBackend>(
+    scene: &B::Scene,
+    colliders: &Query<B::Colliders>,
+    entity: Entity,
+    body: &BodyItem<'_, B>,
+    offset: Vec3,
+    translation: Vec3,
+    settings: &KinematicSettings,
+) -> KinematicMove {
+    let mut result = KinematicMove::default();
+    let mut remaining = translation;
+    for _ in 0..SLIDE_ITERATIONS {
+        let distance = remaining.length();
+        if distance < 1E-6 {
+            break;
+        }
+        let direction = remaining / distance;
+        let hit = match B::cast_shape(
+            scene,
+            colliders,
+            entity,
+            body,
+            offset + result.translation,
+            direction,
+            distance + SKIN_WIDTH,
+        ) {
+            Some(hit) => hit,
+            None => {
+                result.translation += remaining;
+                break;
+            }
+        };
+        let travel = (hit.distance - SKIN_WIDTH).max(0.0).min(distance);
+        result.translation += direction * travel;
+
+        let mut normal = hit.normal;
+        if !settings.walkable(normal) && normal.dot(settings.up) > 0.0 {
+            let wall = horizontal(normal, settings.up);
+            if direction.dot(settings.up) >= 0.0 && wall.length_squared() > 1E-6 {
+                normal = wall.normalize();
+            }
+        }
+        remaining = direction * (distance - travel);
+        remaining -= normal * remaining.dot(normal);
+        result.hits.push(hit);
+    }
+    result
+}
+
+/// `collide_and_slide` that moves across the ground and vertically separately,
+/// climbs ledges up to the step height that block the way and, if snapping to
+/// the ground, drops down steps instead of walking off them
+pub fn collide_slide_and_step<B: CharacterPhysicsBackend>(
+    scene: &B::Scene,
+    colliders: &Query<B::Colliders>,
+    entity: Entity,
+    body: &BodyItem<'_, B>,
+    translation: Vec3,
+    settings: &KinematicSettings,
+) -> KinematicMove {
+    let up = settings.up;
+    let across = horizontal(translation, up);
+    let mut result = slide_from::<B>(scene, colliders, entity, body, Vec3::ZERO, across, settings);
+
+    // Step up: if a wall blocked the move, try the same move from higher up
+    // and then drop back down onto walkable ground
+    let blocked = result.hits.iter().any(|hit| !settings.walkable(hit.normal));
+    if blocked && settings.max_step_height > 0.0 {
+        let rise = B::cast_shape(
+            scene,
+            colliders,
+            entity,
+            body,
+            Vec3::ZERO,
+            up,
+            settings.max_step_height,
+        )
+        .map_or(settings.max_step_height, |hit| hit.distance - SKIN_WIDTH)
+        .max(0.0);
+        let raised = slide_from::<B>(scene, colliders, entity, body, up * rise, across, settings);
+        let down = B::cast_shape(
+            scene,
+            colliders,
+            entity,
+            body,
+            up * rise + raised.translation,
+            -up,
+            rise + SKIN_WIDTH,
+        );
+        let progress =
+            |movement: &KinematicMove| horizontal(movement.translation, up).length_squared();
+        if let Some(down) = down {
+            if settings.walkable(down.normal) && progress(&raised) > progress(&result) + 1E-6 {
+                let step = rise - (down.distance - SKIN_WIDTH).max(0.0);
+                let mut hits = raised.hits;
+                hits.push(down);
+                result = KinematicMove {
+                    translation: up * step + raised.translation,
+                    hits,
+                    step,
+                };
+            }
+        }
+    }
+
+    let fall = slide_from::<B>(
+        scene,
+        colliders,
+        entity,
+        body,
+        result.translation,
+        up * translation.dot(up),
+        settings,
+    );
+    result.translation += fall.translation;
+    result.hits.extend(fall.hits);
+
+    if settings.snap_to_ground {
+        snap_to_ground::<B>(scene, colliders, entity, body, &mut result, settings);
+    }
+    result
+}
+
+/// Drop a move that has left walkable ground down onto ground within the step
+/// height below it
+pub fn snap_to_ground<B: CharacterPhysicsBackend>(
+    scene: &B::Scene,
+    colliders: &Query<B::Colliders>,
+    entity: Entity,
+    body: &BodyItem<'_, B>,
+    movement: &mut KinematicMove,
+    settings: &KinematicSettings,
+) {
+    if movement
+        .hits
+        .iter()
+        .any(|hit| settings.walkable(hit.normal))
+    {
+        return;
+    }
+    let ground = B::cast_shape(
+        scene,
+        colliders,
+        entity,
+        body,
+        movement.translation,
+        -settings.up,
+        settings.max_step_height + SKIN_WIDTH,
+    );
+    if let Some(ground) = ground.filter(|ground| settings.walkable(ground.normal)) {
+        let drop = (ground.distance - SKIN_WIDTH).max(0.0);
+        movement.translation -= settings.up * drop;
+        movement.step -= drop;
+        movement.hits.push(ground);
+    }
+}
+
+/// Height of the ledge blocking a body that moves by `motion` across the
+/// ground, if it is low enough to step up onto
+pub fn find_step<B: CharacterPhysicsBackend>(
+    scene: &B::Scene,
+    colliders: &Query<B::Colliders>,
+    entity: Entity,
+    body: &BodyItem<'_, B>,
+    motion: Vec3,
+    settings: &KinematicSettings,
+) -> Option<f32> {
+    let up = settings.up;
+    let distance = motion.length();
+    if distance < 1E-6 || settings.max_step_height <= 0.0 {
+        return None;
+    }
+    let direction = motion / distance;
+    let wall = B::cast_shape(
+        scene,
+        colliders,
+        entity,
+        body,
+        Vec3::ZERO,
+        direction,
+        distance + SKIN_WIDTH,
+    )?;
+    if settings.walkable(wall.normal) {
+        return None;
+    }
+    let rise = B::cast_shape(
+        scene,
+        colliders,
+        entity,
+        body,
+        Vec3::ZERO,
+        up,
+        settings.max_step_height,
+    )
+    .map_or(settings.max_step_height, |hit| hit.distance - SKIN_WIDTH)
+    .max(0.0);
+    let raised = up * rise;
+    if B::cast_shape(
+        scene,
+        colliders,
+        entity,
+        body,
+        raised,
+        direction,
+        distance + SKIN_WIDTH,
+    )
+    .is_some()
+    {
+        return None;
+    }
+    let ground = B::cast_shape(
+        scene,
+        colliders,
+        entity,
+        body,
+        raised + direction * distance,
+        -up,
+        rise,
+    )?;
+    if !settings.walkable(ground.normal) {
+        return None;
+    }
+    let height = rise - ground.distance + SKIN_WIDTH;
+    if height > SKIN_WIDTH {
+        Some(height)
+    } else {
+        None
+    }
+}
+
+pub fn create_mass<B: CharacterPhysicsBackend>(
+    mut commands: Commands,
+    scene: Res<B::Scene>,
+    mut query: Query<(Entity, B::Body), (With<BodyTag>, Without<Mass>)>,
+) {
+    for (entity, body) in query.iter_mut() {
+        if let Some(mass) = B::mass(&scene, &body) {
+            commands.entity(entity).insert(Mass::new(mass));
+        }
+    }
+}
+
+pub fn body_to_velocity<B: CharacterPhysicsBackend>(
+    scene: Res<B::Scene>,
+    mut query: Query<(B::Body, &mut CharacterController), With<BodyTag>>,
+) {
+    for (body, mut controller) in query.iter_mut() {
+        controller.velocity = B::velocity(&scene, &body);
+    }
+}
+
+pub fn body_to_ground_state<B: CharacterPhysicsBackend>(
+    time: Res<Time>,
+    gravity: Res<Gravity>,
+    scene: Res<B::Scene>,
+    colliders: Query<B::Colliders>,
+    mut platforms: Query<B::Platform, Without<BodyTag>>,
+    mut query: Query<(Entity, B::Body, &CharacterController, &mut GroundState), With<BodyTag>>,
+) {
+    for (entity, body, controller, mut ground_state) in query.iter_mut() {
+        let up = controller.up(&gravity);
+        let ground = B::query_ground(
+            &scene,
+            &colliders,
+            entity,
+            &body,
+            up,
+            controller.ground_check_distance,
+        );
+        match ground {
+            Some(hit) if controller.walkable(hit.normal, up) => {
+                ground_state.set_grounded(hit.entity, hit.point, hit.normal)
+            }
+            Some(hit) => {
+                ground_state.set_sliding(time.delta_seconds(), hit.entity, hit.point, hit.normal)
+            }
+            None => ground_state.set_airborne(time.delta_seconds(), up),
+        }
+        let (platform_velocity, platform_angular_velocity) = ground
+            .and_then(|hit| {
+                let platform = platforms.get_mut(hit.entity?).ok()?;
+                Some(B::platform_velocity(&scene, &platform, hit.point))
+            })
+            .unwrap_or_default();
+        ground_state.platform_velocity = platform_velocity;
+        ground_state.platform_angular_velocity = platform_angular_velocity;
+        ground_state.headroom = if controller.crouching {
+            B::cast_shape(
+                &scene,
+                &colliders,
+                entity,
+                &body,
+                Vec3::ZERO,
+                up,
+                controller.crouch_offset,
+            )
+            .map_or(f32::INFINITY, |hit| hit.distance)
+        } else {
+            f32::INFINITY
+        };
+    }
+}
+
+pub fn controller_to_crouch<B: CharacterPhysicsBackend>(
+    mut commands: Commands,
+    mut query: Query<(Entity, B::Body, &CharacterController, Option<&Crouched>), With<BodyTag>>,
+    parents: Query<&Parent>,
+    mut heads: Query<(Entity, &mut Transform), (With<HeadTag>, Without<BodyTag>)>,
+) {
+    for (entity, mut body, controller, crouched) in query.iter_mut() {
+        if controller.crouching == crouched.is_some() {
+            continue;
+        }
+        // Stand up by exactly as much as was removed when crouching
+        let offset = crouched.map_or(controller.crouch_offset, |crouched| crouched.0);
+        let resize = B::set_crouched(
+            &mut commands,
+            entity,
+            &mut body,
+            controller.crouching,
+            offset,
+        );
+        // The head moves by the height change, less how far the body moved
+        let head_offset = if controller.crouching {
+            commands.entity(entity).insert(Crouched(resize.height));
+            -resize.height - resize.shift
+        } else {
+            commands.entity(entity).remove::<Crouched>();
+            resize.height - resize.shift
+        };
+        for (head, mut transform) in heads.iter_mut() {
+            if belongs_to(head, entity, &parents) {
+                transform.translation.y += head_offset;
+            }
+        }
+    }
+}
+
+pub fn controller_to_gravity<B: CharacterPhysicsBackend>(
+    gravity: Res<Gravity>,
+    mut scene: ResMut<B::Scene>,
+    mut query: Query<(B::Body, &CharacterController, &Mass), With<BodyTag>>,
+) {
+    for (mut body, controller, mass) in query.iter_mut() {
+        // Flying characters float
+        let gravity = if controller.fly {
+            Vec3::ZERO
+        } else {
+            controller.effective_gravity(&gravity)
+        };
+        B::apply_gravity(&mut scene, &mut body, gravity, mass.mass);
+    }
+}
+
+pub fn controller_to_dynamic_impulse<B: CharacterPhysicsBackend>(
+    mut impulses: EventReader<ImpulseEvent>,
+    mut scene: ResMut<B::Scene>,
+    mut query: Query<B::Body, With<BodyTag>>,
+) {
+    let mut totals = HashMap::default();
+    for event in impulses.iter() {
+        *totals.entry(event.entity()).or_insert(Vec3::ZERO) += **event;
+    }
+    for (entity, impulse) in totals {
+        if impulse.length_squared() > 1E-6 {
+            if let Ok(mut body) = query.get_mut(entity) {
+                B::apply_impulse(&mut scene, &mut body, impulse);
+            }
+        }
+    }
+}
+
+pub fn controller_to_dynamic_force<B: CharacterPhysicsBackend>(
+    mut forces: EventReader<ForceEvent>,
+    mut scene: ResMut<B::Scene>,
+    mut query: Query<B::Body, With<BodyTag>>,
+) {
+    // Each force is the rate of change of momentum over one simulation step,
+    // so average those of the steps taken this frame rather than adding them
+    let mut totals = HashMap::default();
+    for event in forces.iter() {
+        let (total, count) = totals.entry(event.entity()).or_insert((Vec3::ZERO, 0));
+        *total += **event;
+        *count += 1;
+    }
+    for (entity, (total, count)) in totals {
+        let force = total / count as f32;
+        if force.length_squared() > 1E-6 {
+            if let Ok(mut body) = query.get_mut(entity) {
+                B::apply_force(&mut scene, &mut body, force);
+            }
+        }
+    }
+}
+
+pub fn controller_to_kinematic_translation<B: CharacterPhysicsBackend>(
+    gravity: Res<Gravity>,
+    mut translations: EventReader<TranslationEvent>,
+    mut step_events: EventWriter<StepEvent>,
+    mut scene: ResMut<B::Scene>,
+    colliders: Query<B::Colliders>,
+    mut query: Query<(B::Body, &mut CharacterController, &mut GroundState), With<BodyTag>>,
+) {
+    let mut totals = HashMap::default();
+    for event in translations.iter() {
+        *totals.entry(event.entity()).or_insert(Vec3::ZERO) += **event;
+    }
+    for (entity, translation) in totals {
+        let (mut body, mut controller, mut ground_state) = match query.get_mut(entity) {
+            Ok(components) => components,
+            Err(_) => continue,
+        };
+        if controller.noclip() {
+            B::translate_kinematic(&mut scene, &mut body, translation);
+            continue;
+        }
+        let up = controller.up(&gravity);
+        let settings = KinematicSettings::new(&controller, up, ground_state.grounded);
+        let movement = B::move_kinematic(
+            &mut scene,
+            &colliders,
+            entity,
+            &mut body,
+            translation,
+            &settings,
+        );
+
+        // Feed the contacts back so that the kinematic velocity does not keep
+        // building up into the surfaces that were hit
+        for hit in movement.hits.iter() {
+            let into_surface = controller.velocity.dot(hit.normal);
+            if into_surface < 0.0 {
+                controller.velocity -= hit.normal * into_surface;
+            }
+            if settings.walkable(hit.normal) && !ground_state.grounded {
+                ground_state.set_grounded(hit.entity, hit.point, hit.normal);
+            }
+        }
+        if movement.step.abs() > 1E-3 {
+            step_events.send(StepEvent::new(entity, movement.step));
+        }
+    }
+}
+
+/// Lift dynamic bodies that are walking into a low ledge up onto it, and keep
+/// them on the ground when they walk down a step
+pub fn controller_to_step<B: CharacterPhysicsBackend>(
+    gravity: Res<Gravity>,
+    mut step_events: EventWriter<StepEvent>,
+    mut scene: ResMut<B::Scene>,
+    colliders: Query<B::Colliders>,
+    mut query: Query<(Entity, B::Body, &CharacterController, &mut GroundState), With<BodyTag>>,
+) {
+    for (entity, mut body, controller, mut ground_state) in query.iter_mut() {
+        if controller.fly || controller.jumping {
+            continue;
+        }
+        let up = controller.up(&gravity);
+        if ground_state.grounded {
+            let settings = KinematicSettings::new(controller, up, true);
+            let motion = horizontal(controller.velocity, up) * controller.dt;
+            if let Some(height) =
+                find_step::<B>(&scene, &colliders, entity, &body, motion, &settings)
+            {
+                B::teleport(&mut scene, &mut body, up * height);
+                step_events.send(StepEvent::new(entity, height));
+            }
+            continue;
+        }
+
+        // Only snap down right after leaving the ground, and not while sliding
+        // or moving upwards
+        let just_left_ground = !ground_state.sliding
+            && ground_state.was_grounded
+            && controller.velocity.dot(up) <= 0.0;
+        if !just_left_ground {
+            continue;
+        }
+        let settings = KinematicSettings::new(controller, up, true);
+        let mut movement = KinematicMove::default();
+        snap_to_ground::<B>(&scene, &colliders, entity, &body, &mut movement, &settings);
+        if let (Some(ground), true) = (movement.hits.last(), movement.step < -1E-3) {
+            B::teleport(&mut scene, &mut body, movement.translation);
+            ground_state.set_grounded(ground.entity, ground.point, ground.normal);
+            step_events.send(StepEvent::new(entity, movement.step));
+        }
+    }
+}
@@ -0,0 +1,127 @@
+/*
+ * Camera boom
+ *
+ * Add a CameraBoom to a camera below a character's head to keep it from
+ * clipping through obstacles. The camera sits on an arm that reaches back
+ * along the camera's view direction from a pivot next to the head. Each frame
+ * a sphere is cast along the arm through the physics backend, and the camera
+ * is pulled in in front of anything that is hit, then eased back out once the
+ * way is clear. The boom runs after transforms have been propagated, so that
+ * it follows the head where it is rendered this frame, and then updates the
+ * camera's GlobalTransform itself. Children of the camera lag a frame behind.
+ */
+
+use crate::{
+    backend::{CastFilter, CharacterPhysicsBackend},
+    easing::{Curve, Easing},
+};
+use bevy::prelude::*;
+
+#[derive(Clone, Debug)]
+pub struct CameraBoom {
+    /// Distance from the pivot to the camera when nothing is in the way
+    pub arm_length: f32,
+    /// Position of the pivot relative to the camera's parent, usually the head
+    pub pivot_offset: Vec3,
+    /// Radius of the cast sphere, which keeps the camera this far from walls
+    pub probe_radius: f32,
+    /// Easing back out to the full arm length once the way is clear
+    pub ease_out: Curve,
+    /// Collision groups, as a bit mask, that do not block the camera. The
+    /// character the camera belongs to never blocks it.
+    pub ignore_groups: u32,
+    /// Current distance from the pivot to the camera
+    pub length: f32,
+}
+
+impl CameraBoom {
+    pub fn new(arm_length: f32, pivot_offset: Vec3) -> Self {
+        Self {
+            arm_length,
+            pivot_offset,
+            probe_radius: 0.2,
+            ease_out: Curve::new(0.5, Easing::QuadraticOut),
+            ignore_groups: 0,
+            length: arm_length,
+        }
+    }
+}
+
+impl Default for CameraBoom {
+    fn default() -> Self {
+        Self::new(4.0, Vec3::ZERO)
+    }
+}
+
+/// Cast along each boom from the propagated transform of the camera's parent
+/// and move the camera to the end of the boom
+pub fn camera_boom<B: CharacterPhysicsBackend>(
+    time: Res<Time>,
+    scene: Res<B::Scene>,
+    colliders: Query<B::Colliders>,
+    parents: Query<&Parent>,
+    global_transforms: Query<&GlobalTransform>,
+    mut query: Query<(Entity, &Parent, &mut Transform, &mut CameraBoom)>,
+) {
+    for (entity, parent, mut transform, mut boom) in query.iter_mut() {
+        let parent_transform = match global_transforms.get(parent.0) {
+            Ok(parent_transform) => parent_transform,
+            Err(_) => continue,
+        };
+        let back = transform.rotation * Vec3::Z;
+        let pivot = parent_transform.mul_vec3(boom.pivot_offset);
+        let end = parent_transform.mul_vec3(boom.pivot_offset + back * boom.arm_length);
+        let distance = (end - pivot).length();
+
+        // The camera's ancestors are the character itself
+        let mut filter = CastFilter {
+            ignore_entities: Vec::new(),
+            ignore_groups: boom.ignore_groups,
+        };
+        let mut ancestor = parent.0;
+        filter.ignore_entities.push(ancestor);
+        while let Ok(next) = parents.get(ancestor) {
+            ancestor = next.0;
+            filter.ignore_entities.push(ancestor);
+        }
+        filter.ignore_entities.push(entity);
+
+        let limit = if distance > 1E-6 {
+            B::cast_sphere(
+                &scene,
+                &colliders,
+                pivot,
+                (end - pivot) / distance,
+                boom.probe_radius,
+                distance,
+                &filter,
+            )
+            .map_or(boom.arm_length, |hit| {
+                hit.distance / distance * boom.arm_length
+            })
+        } else {
+            boom.arm_length
+        };
+
+        // Pull in at once, but ease back out
+        boom.length = if limit < boom.length {
+            limit
+        } else {
+            boom.ease_out
+                .step(boom.length, limit, boom.arm_length, time.delta_seconds())
+        };
+        transform.translation = boom.pivot_offset + back * boom.length;
+    }
+}
+
+/// Bring the GlobalTransform of cameras moved by `camera_boom` up to date
+pub fn camera_boom_to_global_transform(
+    parents: Query<&GlobalTransform, Without<CameraBoom>>,
+    mut query: Query<(&Parent, &Transform, &mut GlobalTransform), With<CameraBoom>>,
+) {
+    for (parent, transform, mut global_transform) in query.iter_mut() {
+        if let Ok(parent_transform) = parents.get(parent.0) {
+            *global_transform = parent_transform.mul_transform(*transform);
+        }
+    }
+}
@@ -5,27 +5,89 @@
  */
 
 use crate::{
+    backend::*,
+    camera::{camera_boom, camera_boom_to_global_transform},
+    easing::{Curve, Easing},
     events::{
-        ForceEvent, ImpulseEvent, LookDeltaEvent, LookEvent, PitchEvent, TranslationEvent, YawEvent,
+        ForceEvent, ImpulseEvent, JumpEvent, LookDeltaEvent, LookEvent, PitchEvent, StepEvent,
+        TranslationEvent, YawEvent,
     },
-    input_map::InputMap,
-    look::{forward_up, input_to_look, LookDirection, LookEntity, MouseSettings},
+    input_map::{create_action_state, input_to_actions, Action, ActionState, InputMap},
+    look::{
+        forward_up, input_to_look, insert_look_settings, look_rotation, LookDirection, LookEntity,
+        MouseSettings,
+    },
+};
+use bevy::{
+    core::{FixedTimestep, FixedTimesteps},
+    prelude::*,
+    transform::TransformSystem,
 };
-use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
 
 pub struct BodyTag;
 pub struct YawTag;
 pub struct HeadTag;
 pub struct CameraTag;
 
-pub struct CharacterControllerPlugin;
+/// Turns input into controller events, without driving any physics bodies
+pub struct CharacterControllerCorePlugin;
 
+pub const CREATE_ACTION_STATE_SYSTEM: &str = "create_action_state";
+pub const INPUT_TO_ACTIONS_SYSTEM: &str = "input_to_actions";
+pub const ACTIONS_TO_INPUT_STATE_SYSTEM: &str = "actions_to_input_state";
 pub const INPUT_TO_EVENTS_SYSTEM: &str = "input_to_events";
 pub const INPUT_TO_LOOK_SYSTEM: &str = "input_to_look";
+pub const INSERT_LOOK_SETTINGS_SYSTEM: &str = "insert_look_settings";
 pub const FORWARD_UP_SYSTEM: &str = "forward_up";
+pub const CAMERA_BOOM_SYSTEM: &str = "camera_boom";
+
+/// Label of the `FixedTimestep` that steps the simulation when
+/// `ControllerTimestep::fixed_timestep` is set
+pub const FIXED_TIMESTEP_LABEL: &str = "character_controller_fixed_timestep";
+
+/// How the controller simulation is stepped. Insert it before adding the
+/// plugins to change it.
+#[derive(Clone, Debug)]
+pub struct ControllerTimestep {
+    /// Most simulation steps taken by a character in one frame, also with a
+    /// `fixed_timestep`
+    pub max_steps_per_frame: u32,
+    /// Step the simulation with Bevy's `FixedTimestep` run criteria at this
+    /// many seconds per step, labelled `FIXED_TIMESTEP_LABEL`, instead of each
+    /// character's own `dt`
+    pub fixed_timestep: Option<f64>,
+}
 
-impl Plugin for CharacterControllerPlugin {
+impl Default for ControllerTimestep {
+    fn default() -> Self {
+        Self {
+            max_steps_per_frame: 5,
+            fixed_timestep: None,
+        }
+    }
+}
+
+impl Plugin for CharacterControllerCorePlugin {
     fn build(&self, app: &mut AppBuilder) {
+        let timestep = app
+            .world_mut()
+            .get_resource_or_insert_with(ControllerTimestep::default)
+            .clone();
+        let input_to_events_system = input_to_events
+            .system()
+            .label(INPUT_TO_EVENTS_SYSTEM)
+            .after(ACTIONS_TO_INPUT_STATE_SYSTEM);
+        match timestep.fixed_timestep {
+            Some(step) => app.add_system_to_stage(
+                CoreStage::PreUpdate,
+                input_to_events_system
+                    .with_run_criteria(FixedTimestep::step(step).with_label(FIXED_TIMESTEP_LABEL)),
+            ),
+            None => app.add_system_to_stage(CoreStage::PreUpdate, input_to_events_system),
+        };
+
         app.add_event::<PitchEvent>()
             .add_event::<YawEvent>()
             .add_event::<LookEvent>()
@@ -33,14 +95,41 @@ impl Plugin for CharacterControllerPlugin {
             .add_event::<TranslationEvent>()
             .add_event::<ImpulseEvent>()
             .add_event::<ForceEvent>()
+            .add_event::<JumpEvent>()
+            .add_event::<StepEvent>()
             .init_resource::<MouseSettings>()
+            .init_resource::<Gravity>()
+            .add_system(
+                create_action_state
+                    .system()
+                    .label(CREATE_ACTION_STATE_SYSTEM),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                input_to_actions
+                    .system()
+                    .label(INPUT_TO_ACTIONS_SYSTEM)
+                    .after(INSERT_LOOK_SETTINGS_SYSTEM),
+            )
             .add_system_to_stage(
                 CoreStage::PreUpdate,
-                input_to_events.system().label(INPUT_TO_EVENTS_SYSTEM),
+                actions_to_input_state
+                    .system()
+                    .label(ACTIONS_TO_INPUT_STATE_SYSTEM)
+                    .after(INPUT_TO_ACTIONS_SYSTEM),
             )
             .add_system_to_stage(
                 CoreStage::PreUpdate,
-                input_to_look.system().label(INPUT_TO_LOOK_SYSTEM),
+                insert_look_settings
+                    .system()
+                    .label(INSERT_LOOK_SETTINGS_SYSTEM),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                input_to_look
+                    .system()
+                    .label(INPUT_TO_LOOK_SYSTEM)
+                    .after(INPUT_TO_ACTIONS_SYSTEM),
             )
             .add_system_to_stage(
                 CoreStage::PreUpdate,
@@ -53,29 +142,223 @@ impl Plugin for CharacterControllerPlugin {
     }
 }
 
-#[derive(Debug, Default)]
+/// Turns input into controller events and applies them to character bodies
+/// through the physics backend `B`. Yaw and pitch are left to the application,
+/// see `controller_to_yaw` and `controller_to_pitch`.
+pub struct CharacterControllerPlugin<B: CharacterPhysicsBackend> {
+    pub mode: ControllerMode,
+    backend: PhantomData<B>,
+}
+
+impl<B: CharacterPhysicsBackend> CharacterControllerPlugin<B> {
+    pub fn new(mode: ControllerMode) -> Self {
+        Self {
+            mode,
+            backend: PhantomData,
+        }
+    }
+}
+
+impl<B: CharacterPhysicsBackend> Plugin for CharacterControllerPlugin<B> {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_plugin(CharacterControllerCorePlugin)
+            .add_system(create_mass::<B>.system().label(CREATE_MASS_SYSTEM))
+            .add_system(
+                create_ground_state
+                    .system()
+                    .label(CREATE_GROUND_STATE_SYSTEM),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                body_to_ground_state::<B>
+                    .system()
+                    .label(BODY_TO_GROUND_STATE_SYSTEM)
+                    .before(INPUT_TO_EVENTS_SYSTEM),
+            )
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                camera_boom::<B>
+                    .system()
+                    .label(CAMERA_BOOM_SYSTEM)
+                    .after(TransformSystem::TransformPropagate),
+            )
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                camera_boom_to_global_transform
+                    .system()
+                    .after(CAMERA_BOOM_SYSTEM),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                controller_to_crouch::<B>
+                    .system()
+                    .label(CONTROLLER_TO_CROUCH_SYSTEM)
+                    .after(INPUT_TO_EVENTS_SYSTEM),
+            );
+
+        // IMPORTANT: The translation/impulse/force systems MUST run before the physics
+        // simulation step, so they are added to the end of PRE_UPDATE
+        match self.mode {
+            ControllerMode::KinematicTranslation => {
+                app.add_system_to_stage(
+                    CoreStage::PreUpdate,
+                    controller_to_kinematic_translation::<B>
+                        .system()
+                        .label(CONTROLLER_TO_KINEMATIC_TRANSLATION_SYSTEM)
+                        .after(INPUT_TO_EVENTS_SYSTEM),
+                );
+            }
+            ControllerMode::DynamicImpulse => {
+                app.add_system_to_stage(
+                    CoreStage::PreUpdate,
+                    body_to_velocity::<B>
+                        .system()
+                        .label(BODY_TO_VELOCITY_SYSTEM)
+                        .before(INPUT_TO_EVENTS_SYSTEM),
+                )
+                .add_system_to_stage(
+                    CoreStage::PreUpdate,
+                    controller_to_dynamic_impulse::<B>
+                        .system()
+                        .label(CONTROLLER_TO_DYNAMIC_IMPULSE_SYSTEM)
+                        .after(INPUT_TO_EVENTS_SYSTEM),
+                );
+            }
+            ControllerMode::DynamicForce => {
+                app.add_system_to_stage(
+                    CoreStage::PreUpdate,
+                    body_to_velocity::<B>
+                        .system()
+                        .label(BODY_TO_VELOCITY_SYSTEM)
+                        .before(INPUT_TO_EVENTS_SYSTEM),
+                )
+                .add_system_to_stage(
+                    CoreStage::PreUpdate,
+                    controller_to_dynamic_force::<B>
+                        .system()
+                        .label(CONTROLLER_TO_DYNAMIC_FORCE_SYSTEM)
+                        .after(INPUT_TO_EVENTS_SYSTEM),
+                );
+            }
+        }
+        // Only the label of the system that applies the events in this mode exists
+        let dynamic_system = match self.mode {
+            ControllerMode::KinematicTranslation => None,
+            ControllerMode::DynamicImpulse => Some(CONTROLLER_TO_DYNAMIC_IMPULSE_SYSTEM),
+            ControllerMode::DynamicForce => Some(CONTROLLER_TO_DYNAMIC_FORCE_SYSTEM),
+        };
+        if let Some(dynamic_system) = dynamic_system {
+            app.add_system_to_stage(
+                CoreStage::PreUpdate,
+                controller_to_gravity::<B>
+                    .system()
+                    .label(CONTROLLER_TO_GRAVITY_SYSTEM)
+                    .after(INPUT_TO_EVENTS_SYSTEM)
+                    .before(dynamic_system),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                controller_to_step::<B>
+                    .system()
+                    .label(CONTROLLER_TO_STEP_SYSTEM)
+                    .after(INPUT_TO_EVENTS_SYSTEM)
+                    .before(dynamic_system),
+            );
+        }
+
+        B::build(app);
+    }
+}
+
+/// Input for the next simulation step, see `actions_to_input_state`
+#[derive(Clone, Debug, Default)]
 pub struct InputState {
-    pub forward: bool,
-    pub backward: bool,
-    pub left: bool,
-    pub right: bool,
+    /// Movement, x to the right and y forward, with a length of at most 1
+    pub movement: Vec2,
+    /// Whether the movement came from an analog binding
+    pub analog: bool,
     pub run: bool,
     pub jump: bool,
+    /// Whether the jump button is held, while `jump` is whether it was pressed
+    pub jump_held: bool,
+    pub crouch: bool,
     pub up: bool,
     pub down: bool,
 }
 
-#[derive(Debug)]
+/// Only the tuning is serialized, the simulation state is skipped and any
+/// missing tuning takes its default value
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
 pub struct CharacterController {
     pub input_map: InputMap,
+    #[serde(skip)]
     pub fly: bool,
+    pub fly_mode: FlyMode,
     pub walk_speed: f32,
     pub run_speed: f32,
+    pub crouch_speed: f32,
     pub jump_speed: f32,
+    /// What happens when the jump button is released while still rising
+    pub jump_release: JumpRelease,
+    /// Jump speeds of the jumps that can be made in the air before landing,
+    /// in order. Empty for no air jumps.
+    pub air_jump_speeds: Vec<f32>,
+    /// Seconds after walking off a ledge during which jumping is still allowed
+    pub coyote_time: f32,
+    /// Seconds before landing during which a jump press is remembered
+    pub jump_buffer_time: f32,
+    /// How far the head and the top of the collider are lowered when crouching
+    pub crouch_offset: f32,
+    /// Speeding up from rest to the target speed
+    pub acceleration: Curve,
+    /// Slowing down to rest from the run speed
+    pub deceleration: Curve,
+    /// Braking when moving against or across the input direction
+    pub turn_around: Curve,
+    /// Any change of speed while airborne
+    pub air_acceleration: Curve,
+    /// Fraction of the air acceleration that input is allowed to apply, from 0
+    /// for no air control to 1 for full control
+    pub air_control: f32,
+    /// Horizontal speed that input can reach in the air. Faster momentum from
+    /// takeoff is kept.
+    pub max_air_speed: f32,
+    /// Steepest ground, in radians, that the character can walk on. Steeper
+    /// slopes make it slide down.
+    pub max_slope_angle: f32,
+    /// Highest ledge that the character steps up onto, and highest step that
+    /// it walks down without leaving the ground
+    pub max_step_height: f32,
+    /// Gravity for this character, or the `Gravity` resource if `None`. Up for
+    /// movement and jumping is opposite to it.
+    pub gravity: Option<Vec3>,
+    #[serde(skip)]
     pub velocity: Vec3,
+    #[serde(skip)]
     pub jumping: bool,
+    /// Whether the jump button was released early during the current jump
+    #[serde(skip)]
+    pub jump_released: bool,
+    /// Number of air jumps made since leaving the ground
+    #[serde(skip)]
+    pub air_jumps_used: usize,
+    /// Horizontal speed when the character last left the ground
+    #[serde(skip)]
+    pub takeoff_speed: f32,
+    /// Remaining seconds of a buffered jump press
+    #[serde(skip)]
+    pub jump_buffer: f32,
+    #[serde(skip)]
+    pub crouching: bool,
+    pub ground_check_distance: f32,
     pub dt: f32,
+    #[serde(skip)]
     pub sim_to_render: f32,
+    /// Number of simulation steps taken in the current frame
+    #[serde(skip)]
+    pub steps_this_frame: u32,
+    #[serde(skip)]
     pub input_state: InputState,
 }
 
@@ -84,18 +367,362 @@ impl Default for CharacterController {
         Self {
             input_map: InputMap::default(),
             fly: false,
+            fly_mode: FlyMode::Collide,
             walk_speed: 5.0,
             run_speed: 8.0,
+            crouch_speed: 2.5,
             jump_speed: 6.0,
+            jump_release: JumpRelease::CutVelocity(0.5),
+            air_jump_speeds: Vec::new(),
+            coyote_time: 0.1,
+            jump_buffer_time: 0.1,
+            crouch_offset: 0.6,
+            acceleration: Curve::new(0.15, Easing::QuadraticOut),
+            deceleration: Curve::new(0.1, Easing::QuadraticOut),
+            turn_around: Curve::new(0.1, Easing::Linear),
+            air_acceleration: Curve::new(0.4, Easing::Linear),
+            air_control: 0.5,
+            max_air_speed: 8.0,
+            max_slope_angle: std::f32::consts::FRAC_PI_4,
+            max_step_height: 0.3,
+            gravity: None,
             velocity: Vec3::ZERO,
             jumping: false,
+            jump_released: false,
+            air_jumps_used: 0,
+            takeoff_speed: 0.0,
+            jump_buffer: 0.0,
+            crouching: false,
+            ground_check_distance: 0.1,
             dt: 1.0 / 60.0,
             sim_to_render: 0.0,
+            steps_this_frame: 0,
             input_state: InputState::default(),
         }
     }
 }
 
+impl CharacterController {
+    /// Replace the tuning with that of `tuning`, keeping the input map, which
+    /// may have been rebound, and the simulation state
+    pub fn set_tuning(&mut self, tuning: &CharacterController) {
+        let tuning = tuning.clone();
+        // Every field is listed, so that a new one has to be sorted into tuning
+        // or state here before this compiles
+        *self = CharacterController {
+            input_map: std::mem::take(&mut self.input_map),
+            fly_mode: tuning.fly_mode,
+            walk_speed: tuning.walk_speed,
+            run_speed: tuning.run_speed,
+            crouch_speed: tuning.crouch_speed,
+            jump_speed: tuning.jump_speed,
+            jump_release: tuning.jump_release,
+            air_jump_speeds: tuning.air_jump_speeds,
+            coyote_time: tuning.coyote_time,
+            jump_buffer_time: tuning.jump_buffer_time,
+            crouch_offset: tuning.crouch_offset,
+            acceleration: tuning.acceleration,
+            deceleration: tuning.deceleration,
+            turn_around: tuning.turn_around,
+            air_acceleration: tuning.air_acceleration,
+            air_control: tuning.air_control,
+            max_air_speed: tuning.max_air_speed,
+            max_slope_angle: tuning.max_slope_angle,
+            max_step_height: tuning.max_step_height,
+            gravity: tuning.gravity,
+            ground_check_distance: tuning.ground_check_distance,
+            dt: tuning.dt,
+            fly: self.fly,
+            velocity: self.velocity,
+            jumping: self.jumping,
+            jump_released: self.jump_released,
+            air_jumps_used: self.air_jumps_used,
+            takeoff_speed: self.takeoff_speed,
+            jump_buffer: self.jump_buffer,
+            crouching: self.crouching,
+            sim_to_render: self.sim_to_render,
+            steps_this_frame: self.steps_this_frame,
+            input_state: std::mem::take(&mut self.input_state),
+        };
+    }
+
+    /// Advance the simulation clock by `delta_seconds` and return the number
+    /// of `dt` steps that are due, at most `max_steps`. Time beyond that is
+    /// dropped, so that one slow frame does not make the next ones slower.
+    pub fn take_steps(&mut self, delta_seconds: f32, max_steps: u32) -> u32 {
+        if self.dt <= 0.0 {
+            return 0;
+        }
+        self.sim_to_render += delta_seconds;
+        let due = (self.sim_to_render / self.dt).floor();
+        self.sim_to_render -= due * self.dt;
+        (due as u32).min(max_steps)
+    }
+
+    /// How far rendering is between the last simulation step and the next,
+    /// from 0 to 1, for interpolating between simulated transforms
+    pub fn interpolation_alpha(&self) -> f32 {
+        if self.dt > 0.0 {
+            (self.sim_to_render / self.dt).max(0.0).min(1.0)
+        } else {
+            0.0
+        }
+    }
+
+    /// Whether the character is flying through everything
+    pub fn noclip(&self) -> bool {
+        self.fly && self.fly_mode == FlyMode::Noclip
+    }
+
+    /// Change `velocity` towards `target` for one simulation step, following
+    /// the acceleration curves
+    pub fn accelerate(&self, velocity: Vec3, target: Vec3, airborne: bool) -> Vec3 {
+        let (acceleration, deceleration, turn_around) = if airborne {
+            (
+                &self.air_acceleration,
+                &self.air_acceleration,
+                &self.air_acceleration,
+            )
+        } else {
+            (&self.acceleration, &self.deceleration, &self.turn_around)
+        };
+        let reference = self.run_speed.max(target.length());
+
+        let target_speed = target.length();
+        if target_speed < 1E-6 {
+            let speed = velocity.length();
+            if speed < 1E-6 {
+                return Vec3::ZERO;
+            }
+            return velocity / speed * deceleration.step(speed, 0.0, reference, self.dt);
+        }
+
+        // Speed up or slow down along the target direction, and brake any
+        // movement across it
+        let direction = target / target_speed;
+        let along = velocity.dot(direction);
+        let across = velocity - direction * along;
+        let along = if along < 0.0 {
+            -turn_around.step(-along, 0.0, reference, self.dt)
+        } else if along <= target_speed {
+            acceleration.step(along, target_speed, reference, self.dt)
+        } else {
+            deceleration.step(along, target_speed, reference, self.dt)
+        };
+        let across_speed = across.length();
+        let across = if across_speed > 1E-6 {
+            across / across_speed * turn_around.step(across_speed, 0.0, reference, self.dt)
+        } else {
+            Vec3::ZERO
+        };
+        direction * along + across
+    }
+
+    /// Steer `velocity` towards `target` for one simulation step in the air.
+    /// Without input the velocity is kept, and input can only speed the
+    /// character up to `max_air_speed`, or keep the speed it took off with.
+    pub fn steer_in_air(&self, velocity: Vec3, target: Vec3) -> Vec3 {
+        let input_speed = target.length();
+        if input_speed < 1E-6 {
+            return velocity;
+        }
+        let speed = velocity.length();
+        let limit = self.max_air_speed.max(self.takeoff_speed);
+        let target_speed = input_speed.min(self.max_air_speed).max(speed.min(limit));
+        let target = target / input_speed * target_speed;
+
+        let steered = self.accelerate(velocity, target, true);
+        let steered = velocity + (steered - velocity) * self.air_control.max(0.0).min(1.0);
+        let steered_speed = steered.length();
+        let max_speed = limit.max(speed);
+        if steered_speed > max_speed {
+            steered / steered_speed * max_speed
+        } else {
+            steered
+        }
+    }
+
+    /// Whether ground with `normal` is shallow enough to walk on
+    pub fn walkable(&self, normal: Vec3, up: Vec3) -> bool {
+        normal.dot(up) >= self.max_slope_angle.cos()
+    }
+
+    pub fn effective_gravity(&self, gravity: &Gravity) -> Vec3 {
+        self.gravity.unwrap_or(gravity.0)
+    }
+
+    /// The direction opposite to gravity, or y if there is no gravity
+    pub fn up(&self, gravity: &Gravity) -> Vec3 {
+        Gravity(self.effective_gravity(gravity)).up()
+    }
+}
+
+/// How a flying character, which is not affected by gravity, interacts with
+/// the world
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum FlyMode {
+    /// Keep colliding with other bodies
+    Collide,
+    /// Pass through other bodies
+    Noclip,
+}
+
+/// Variable jump height: what happens when the jump button is released before
+/// the top of a jump
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum JumpRelease {
+    /// Keep the full jump height
+    Full,
+    /// Multiply the upward velocity by the factor once
+    CutVelocity(f32),
+    /// Multiply gravity by the factor for the rest of the ascent
+    ScaleGravity(f32),
+}
+
+/// Default gravity for characters that do not set their own
+#[derive(Clone, Copy, Debug)]
+pub struct Gravity(pub Vec3);
+
+impl Default for Gravity {
+    fn default() -> Self {
+        Self(-9.81 * Vec3::Y)
+    }
+}
+
+impl Gravity {
+    /// The direction opposite to gravity, or y if there is no gravity
+    pub fn up(&self) -> Vec3 {
+        if self.0.length_squared() > 1E-6 {
+            -self.0.normalize()
+        } else {
+            Vec3::Y
+        }
+    }
+}
+
+/// The part of `vector` perpendicular to `up`
+pub fn horizontal(vector: Vec3, up: Vec3) -> Vec3 {
+    vector - up * vector.dot(up)
+}
+
+/// Forward and right directions across the ground for a character looking
+/// along `look`. These stay defined when looking straight along `up`.
+pub fn ground_axes(look: &LookDirection, up: Vec3) -> (Vec3, Vec3) {
+    let mut right = horizontal(look.right, up).normalize_or_zero();
+    if right == Vec3::ZERO {
+        right = look.forward.cross(up).normalize_or_zero();
+    }
+    if right == Vec3::ZERO {
+        right = up.any_orthonormal_vector();
+    }
+    (up.cross(right), right)
+}
+
+/// Tilt `vector`, which is perpendicular to `up`, onto the ground plane with
+/// `normal`, keeping its length and its direction seen from above
+pub fn along_ground(vector: Vec3, normal: Vec3, up: Vec3) -> Vec3 {
+    let normal_up = normal.dot(up);
+    if normal_up < 1E-3 {
+        return vector;
+    }
+    let tilted = vector - up * (vector.dot(normal) / normal_up);
+    let length = tilted.length();
+    if length < 1E-6 {
+        return Vec3::ZERO;
+    }
+    tilted / length * vector.length()
+}
+
+/// Ground contact information reported by the physics backends. While
+/// airborne, the normal is the character's up direction.
+#[derive(Debug)]
+pub struct GroundState {
+    pub grounded: bool,
+    /// Whether the character was grounded before the latest ground check
+    pub was_grounded: bool,
+    /// Standing on ground that is too steep to walk on
+    pub sliding: bool,
+    pub normal: Vec3,
+    pub point: Vec3,
+    pub entity: Option<Entity>,
+    /// Infinite for characters that have not touched the ground since spawning
+    pub time_since_grounded: f32,
+    /// Free space above a crouching character, checked before standing up
+    pub headroom: f32,
+    /// Velocity of the ground under the character, for moving platforms
+    pub platform_velocity: Vec3,
+    /// Angular velocity of the ground under the character
+    pub platform_angular_velocity: Vec3,
+}
+
+impl Default for GroundState {
+    fn default() -> Self {
+        Self {
+            grounded: false,
+            was_grounded: false,
+            sliding: false,
+            normal: Vec3::Y,
+            point: Vec3::ZERO,
+            entity: None,
+            time_since_grounded: f32::INFINITY,
+            headroom: f32::INFINITY,
+            platform_velocity: Vec3::ZERO,
+            platform_angular_velocity: Vec3::ZERO,
+        }
+    }
+}
+
+impl GroundState {
+    pub fn set_grounded(&mut self, entity: Option<Entity>, point: Vec3, normal: Vec3) {
+        self.was_grounded = self.grounded;
+        self.grounded = true;
+        self.sliding = false;
+        self.normal = normal;
+        self.point = point;
+        self.entity = entity;
+        self.time_since_grounded = 0.0;
+    }
+
+    pub fn set_sliding(
+        &mut self,
+        delta_seconds: f32,
+        entity: Option<Entity>,
+        point: Vec3,
+        normal: Vec3,
+    ) {
+        self.was_grounded = self.grounded;
+        self.grounded = false;
+        self.sliding = true;
+        self.normal = normal;
+        self.point = point;
+        self.entity = entity;
+        self.time_since_grounded += delta_seconds;
+    }
+
+    pub fn set_airborne(&mut self, delta_seconds: f32, up: Vec3) {
+        self.was_grounded = self.grounded;
+        self.grounded = false;
+        self.sliding = false;
+        self.normal = up;
+        self.entity = None;
+        self.time_since_grounded += delta_seconds;
+    }
+}
+
+pub fn create_ground_state(
+    mut commands: Commands,
+    query: Query<Entity, (With<CharacterController>, Without<GroundState>)>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).insert(GroundState::default());
+    }
+}
+
+/// Added to a character body while its collider is shrunk for crouching, with
+/// the height that was removed
+#[derive(Clone, Copy, Debug)]
+pub struct Crouched(pub f32);
+
 #[derive(Debug)]
 pub struct Mass {
     pub mass: f32,
@@ -107,164 +734,450 @@ impl Mass {
     }
 }
 
+/// Update each character's input state from its actions once per frame.
+/// Held input is taken from the latest frame, while presses are kept until a
+/// simulation step has used them. Also starts counting the frame's steps.
+pub fn actions_to_input_state(mut query: Query<(&mut CharacterController, &ActionState)>) {
+    for (mut controller, action_state) in query.iter_mut() {
+        controller.steps_this_frame = 0;
+        if action_state.just_pressed(Action::ToggleFly) {
+            controller.fly = !controller.fly;
+        }
+        let jump = controller.input_state.jump || action_state.just_pressed(Action::Jump);
+        controller.input_state = InputState {
+            movement: action_state.axis(Action::Move),
+            analog: action_state.analog(Action::Move),
+            run: action_state.pressed(Action::Run),
+            jump,
+            jump_held: action_state.pressed(Action::Jump),
+            crouch: action_state.pressed(Action::Crouch),
+            up: action_state.pressed(Action::FlyUp),
+            down: action_state.pressed(Action::FlyDown),
+        };
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn input_to_events(
     time: Res<Time>,
-    keyboard_input: Res<Input<KeyCode>>,
+    timestep: Res<ControllerTimestep>,
+    fixed_timesteps: Option<Res<FixedTimesteps>>,
+    gravity: Res<Gravity>,
     mut translation_events: EventWriter<TranslationEvent>,
     mut impulse_events: EventWriter<ImpulseEvent>,
     mut force_events: EventWriter<ForceEvent>,
-    mut controller_query: Query<(&Mass, &LookEntity, &mut CharacterController)>,
+    mut jump_events: EventWriter<JumpEvent>,
+    mut controller_query: Query<(
+        Entity,
+        &Mass,
+        &LookEntity,
+        &mut CharacterController,
+        Option<&GroundState>,
+    )>,
     look_direction_query: Query<&LookDirection>,
 ) {
-    let xz = Vec3::new(1.0, 0.0, 1.0);
-    for (mass, look_entity, mut controller) in controller_query.iter_mut() {
-        controller.sim_to_render += time.delta_seconds();
+    // With a fixed timestep, the run criteria decides how often this system
+    // runs and each run is one simulation step
+    let fixed = fixed_timesteps
+        .as_ref()
+        .filter(|_| timestep.fixed_timestep.is_some())
+        .and_then(|fixed_timesteps| fixed_timesteps.get(FIXED_TIMESTEP_LABEL));
 
-        if keyboard_input.just_pressed(controller.input_map.key_fly) {
-            controller.fly = !controller.fly;
-        }
-        if keyboard_input.pressed(controller.input_map.key_forward) {
-            controller.input_state.forward = true;
-        }
-        if keyboard_input.pressed(controller.input_map.key_backward) {
-            controller.input_state.backward = true;
-        }
-        if keyboard_input.pressed(controller.input_map.key_right) {
-            controller.input_state.right = true;
-        }
-        if keyboard_input.pressed(controller.input_map.key_left) {
-            controller.input_state.left = true;
-        }
-        if keyboard_input.pressed(controller.input_map.key_run) {
-            controller.input_state.run = true;
-        }
-        if keyboard_input.just_pressed(controller.input_map.key_jump) {
-            controller.input_state.jump = true;
-        }
-        if keyboard_input.pressed(controller.input_map.key_fly_up) {
-            controller.input_state.up = true;
-        }
-        if keyboard_input.pressed(controller.input_map.key_fly_down) {
-            controller.input_state.down = true;
-        }
+    for (entity, mass, look_entity, mut controller, ground_state) in controller_query.iter_mut() {
+        let steps = match fixed {
+            Some(state) => {
+                controller.dt = state.step as f32;
+                controller.sim_to_render = state.accumulator as f32;
+                // The run criteria keeps running this system until its
+                // accumulator is drained, so steps beyond the cap drop their time
+                if controller.steps_this_frame < timestep.max_steps_per_frame {
+                    1
+                } else {
+                    0
+                }
+            }
+            None => controller.take_steps(time.delta_seconds(), timestep.max_steps_per_frame),
+        };
+        controller.steps_this_frame += steps;
 
-        if controller.sim_to_render < controller.dt {
-            continue;
-        }
-        // Calculate the remaining simulation to render time after all
-        // simulation steps were taken
-        controller.sim_to_render %= controller.dt;
+        for _ in 0..steps {
+            let look = look_direction_query
+                .get_component::<LookDirection>(look_entity.0)
+                .expect("Failed to get LookDirection from Entity");
 
-        let look = look_direction_query
-            .get_component::<LookDirection>(look_entity.0)
-            .expect("Failed to get LookDirection from Entity");
+            // Calculate forward / right / up vectors. When not flying, movement is
+            // perpendicular to gravity.
+            let character_gravity = controller.effective_gravity(&gravity);
+            let character_up = controller.up(&gravity);
+            let (forward, right, up) = if controller.fly {
+                (look.forward, look.right, look.up)
+            } else {
+                let (forward, right) = ground_axes(look, character_up);
+                (forward, right, character_up)
+            };
 
-        // Calculate forward / right / up vectors
-        let (forward, right, up) = if controller.fly {
-            (look.forward, look.right, look.up)
-        } else {
-            (
-                (look.forward * xz).normalize(),
-                (look.right * xz).normalize(),
-                Vec3::Y,
-            )
-        };
+            // Calculate the desired velocity based on input
+            let movement = controller.input_state.movement;
+            let mut desired_velocity = right * movement.x + forward * movement.y;
+            if controller.input_state.up {
+                desired_velocity += up;
+            }
+            if controller.input_state.down {
+                desired_velocity -= up;
+            }
 
-        // Calculate the desired velocity based on input
-        let mut desired_velocity = Vec3::ZERO;
-        if controller.input_state.forward {
-            desired_velocity += forward;
-        }
-        if controller.input_state.backward {
-            desired_velocity -= forward;
-        }
-        if controller.input_state.right {
-            desired_velocity += right;
-        }
-        if controller.input_state.left {
-            desired_velocity -= right;
-        }
-        if controller.input_state.up {
-            desired_velocity += up;
-        }
-        if controller.input_state.down {
-            desired_velocity -= up;
-        }
+            // Crouch while the key is held, and only stand up again if there is room
+            if controller.input_state.crouch {
+                controller.crouching = true;
+            } else if controller.crouching
+                && ground_state.map_or(true, |ground| ground.headroom >= controller.crouch_offset)
+            {
+                controller.crouching = false;
+            }
 
-        // Limit x/z velocity to walk/run speed
-        let speed = if controller.input_state.run {
-            controller.run_speed
-        } else {
-            controller.walk_speed
-        };
-        desired_velocity = if desired_velocity.length_squared() > 1E-6 {
-            desired_velocity.normalize() * speed
-        } else {
-            // No input - apply damping to the x/z of the current velocity
-            controller.velocity * 0.5 * xz
-        };
+            // Limit x/z velocity to crouch/walk/run speed. Analog movement scales
+            // from walking to running with how far the stick is pushed.
+            let speed = if controller.crouching {
+                controller.crouch_speed
+            } else if controller.input_state.run {
+                controller.run_speed
+            } else if controller.input_state.analog {
+                movement.length().mul_add(
+                    controller.run_speed - controller.walk_speed,
+                    controller.walk_speed,
+                )
+            } else {
+                controller.walk_speed
+            };
+            if desired_velocity.length_squared() > 1E-6 {
+                desired_velocity = desired_velocity.normalize() * speed;
+            }
+            if !controller.fly {
+                desired_velocity = horizontal(desired_velocity, up);
+            }
 
-        // Handle jumping
-        let was_jumping = controller.jumping;
-        if !controller.fly {
-            desired_velocity.y = if controller.input_state.jump {
-                controller.jumping = true;
-                controller.jump_speed
+            // Handle landing. Without a GroundState from a backend, the character is
+            // assumed to be on the ground until it jumps.
+            let on_ground = ground_state.map_or(!controller.jumping, |ground| ground.grounded);
+
+            // Move relative to the platform the character is standing on, and keep
+            // its velocity when leaving it
+            let platform_velocity = match ground_state {
+                Some(ground) if on_ground && !controller.fly => ground.platform_velocity,
+                _ => Vec3::ZERO,
+            };
+            controller.velocity -= platform_velocity;
+
+            if controller.jumping && on_ground && controller.velocity.dot(up) <= 0.0 {
+                controller.jumping = false;
+            }
+            let grounded = on_ground && !controller.jumping;
+            if grounded {
+                controller.air_jumps_used = 0;
+            }
+
+            // Walk along walkable ground rather than off it, so that the character
+            // does not bounce down ramps. On ground that is too steep, input can
+            // not push up the slope.
+            let ground_normal = ground_state.map_or(up, |ground| ground.normal);
+            let sliding = ground_state.map_or(false, |ground| ground.sliding);
+            if !controller.fly && grounded {
+                desired_velocity = along_ground(desired_velocity, ground_normal, up);
+            } else if !controller.fly && sliding {
+                let downhill = horizontal(ground_normal, up);
+                if downhill.length_squared() > 1E-6 {
+                    let downhill = downhill.normalize();
+                    let uphill = -desired_velocity.dot(downhill);
+                    if uphill > 0.0 {
+                        desired_velocity += downhill * uphill;
+                    }
+                }
+            }
+
+            // Ease the current velocity towards the desired velocity
+            let current_velocity = if controller.fly {
+                controller.velocity
+            } else if grounded {
+                controller.velocity - ground_normal * controller.velocity.dot(ground_normal)
             } else {
-                0.0
+                horizontal(controller.velocity, up)
+            };
+            desired_velocity = if controller.fly || grounded {
+                controller.accelerate(current_velocity, desired_velocity, false)
+            } else {
+                controller.steer_in_air(current_velocity, desired_velocity)
             };
-        }
 
-        // Calculate impulse - the desired momentum change for the time period
-        let delta_velocity =
-            desired_velocity - controller.velocity * if controller.fly { Vec3::ONE } else { xz };
-        let impulse = delta_velocity * mass.mass;
-        if impulse.length_squared() > 1E-6 {
-            impulse_events.send(ImpulseEvent::new(&impulse));
-        }
+            // Handle jumping. A press is buffered for a while in case it came just
+            // before landing, and jumping is still allowed for a while after
+            // walking off a ledge. Otherwise, an air jump is used if any are left.
+            if controller.input_state.jump {
+                controller.jump_buffer = controller.jump_buffer_time;
+            }
+            let wants_jump = controller.input_state.jump || controller.jump_buffer > 0.0;
+            let coyote = ground_state.map_or(false, |ground| {
+                !controller.jumping && ground.time_since_grounded <= controller.coyote_time
+            });
+            let jump_speed = if controller.fly || !wants_jump {
+                None
+            } else if grounded || coyote {
+                Some(controller.jump_speed)
+            } else if controller.input_state.jump {
+                let speed = controller
+                    .air_jump_speeds
+                    .get(controller.air_jumps_used)
+                    .copied();
+                if speed.is_some() {
+                    controller.air_jumps_used += 1;
+                }
+                speed
+            } else {
+                None
+            };
+            let jump = jump_speed.is_some();
+            let mut delta_velocity = desired_velocity - current_velocity;
+            let mut gravity_scale = 1.0;
+            if let Some(jump_speed) = jump_speed {
+                controller.jumping = true;
+                controller.jump_released = false;
+                controller.jump_buffer = 0.0;
+                desired_velocity += up * jump_speed;
+                // Jump from a standstill, even if already falling
+                delta_velocity += up * (jump_speed - controller.velocity.dot(up).min(0.0));
+                let air_jump = if grounded || coyote {
+                    0
+                } else {
+                    controller.air_jumps_used
+                };
+                jump_events.send(JumpEvent::new(entity, air_jump, jump_speed));
+            } else {
+                controller.jump_buffer = (controller.jump_buffer - controller.dt).max(0.0);
 
-        // Calculate force - the desired rate of change of momentum for the time period
-        let force = impulse / controller.dt;
-        if force.length_squared() > 1E-6 {
-            force_events.send(ForceEvent::new(&force));
-        }
+                // Releasing the jump button early makes for a lower jump
+                let rising = controller.velocity.dot(up);
+                if controller.jumping && rising > 0.0 && !controller.input_state.jump_held {
+                    match controller.jump_release {
+                        JumpRelease::Full => {}
+                        JumpRelease::CutVelocity(factor) => {
+                            if !controller.jump_released {
+                                let cut = up * rising * (1.0 - factor);
+                                controller.velocity -= cut;
+                                delta_velocity -= cut;
+                            }
+                        }
+                        JumpRelease::ScaleGravity(factor) => {
+                            gravity_scale = factor;
+                            // Dynamic bodies already get gravity from the backend
+                            delta_velocity += character_gravity * (factor - 1.0) * controller.dt;
+                        }
+                    }
+                    controller.jump_released = true;
+                }
+            }
 
-        controller.velocity.x = desired_velocity.x;
-        controller.velocity.z = desired_velocity.z;
-        controller.velocity.y = if !controller.fly && was_jumping {
-            // Apply gravity for kinematic simulation
-            (-9.81f32).mul_add(controller.dt, controller.velocity.y)
-        } else {
-            desired_velocity.y
-        };
+            // Calculate impulse - the desired momentum change for the time period
+            let impulse = delta_velocity * mass.mass;
+            if impulse.length_squared() > 1E-6 {
+                impulse_events.send(ImpulseEvent::new(entity, &impulse));
+            }
 
-        let translation = controller.velocity * controller.dt;
-        if translation.length_squared() > 1E-6 {
-            translation_events.send(TranslationEvent::new(&translation));
+            // Calculate force - the desired rate of change of momentum for the time period
+            let force = impulse / controller.dt;
+            if force.length_squared() > 1E-6 {
+                force_events.send(ForceEvent::new(entity, &force));
+            }
+
+            controller.velocity = if !controller.fly && !grounded && !jump {
+                // Apply gravity for kinematic simulation
+                horizontal(desired_velocity, up)
+                    + up * controller.velocity.dot(up)
+                    + character_gravity * gravity_scale * controller.dt
+            } else {
+                desired_velocity
+            };
+            controller.velocity += platform_velocity;
+
+            if grounded || jump {
+                controller.takeoff_speed = horizontal(controller.velocity, up).length();
+            }
+
+            let translation = controller.velocity * controller.dt;
+            if translation.length_squared() > 1E-6 {
+                translation_events.send(TranslationEvent::new(entity, &translation));
+            }
+
+            // A press is only used by the first step after it
+            controller.input_state.jump = false;
         }
+    }
+}
 
-        controller.input_state = InputState::default();
+/// Whether `entity` is `body` or one of its descendants
+pub(crate) fn belongs_to(entity: Entity, body: Entity, parents: &Query<&Parent>) -> bool {
+    let mut current = entity;
+    loop {
+        if current == body {
+            return true;
+        }
+        match parents.get(current) {
+            Ok(parent) => current = parent.0,
+            Err(_) => return false,
+        }
     }
 }
 
 pub fn controller_to_yaw(
+    gravity: Res<Gravity>,
     mut yaws: EventReader<YawEvent>,
-    mut query: Query<&mut Transform, With<YawTag>>,
+    parents: Query<&Parent>,
+    controllers: Query<&CharacterController>,
+    mut query: Query<(Entity, &mut Transform), With<YawTag>>,
 ) {
-    if let Some(yaw) = yaws.iter().next() {
-        for mut transform in query.iter_mut() {
-            transform.rotation = Quat::from_rotation_y(**yaw);
+    for yaw in yaws.iter() {
+        // Yaw about the character's up. The body itself is not rotated.
+        let up = controllers
+            .get(yaw.entity())
+            .map_or_else(|_| gravity.up(), |controller| controller.up(&gravity));
+        for (entity, mut transform) in query.iter_mut() {
+            if belongs_to(entity, yaw.entity(), &parents) {
+                transform.rotation = look_rotation(Vec3::new(**yaw, 0.0, 0.0), up);
+            }
         }
     }
 }
 
 pub fn controller_to_pitch(
     mut pitches: EventReader<PitchEvent>,
-    mut query: Query<&mut Transform, With<HeadTag>>,
+    parents: Query<&Parent>,
+    mut query: Query<(Entity, &mut Transform), With<HeadTag>>,
 ) {
-    if let Some(pitch) = pitches.iter().next() {
-        for mut transform in query.iter_mut() {
-            transform.rotation = Quat::from_rotation_ypr(0.0, **pitch, 0.0);
+    for pitch in pitches.iter() {
+        for (entity, mut transform) in query.iter_mut() {
+            if belongs_to(entity, pitch.entity(), &parents) {
+                transform.rotation = Quat::from_rotation_ypr(0.0, **pitch, 0.0);
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input_map::Binding;
+    use std::f32::consts::FRAC_PI_2;
+
+    fn controller_with_dt(dt: f32) -> CharacterController {
+        CharacterController {
+            dt,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn take_steps_keeps_remainder() {
+        let mut controller = controller_with_dt(0.25);
+        assert_eq!(controller.take_steps(0.6, 5), 2);
+        assert!((controller.sim_to_render - 0.1).abs() < 1E-6);
+        assert!((controller.interpolation_alpha() - 0.4).abs() < 1E-5);
+        assert_eq!(controller.take_steps(0.2, 5), 1);
+        assert!((controller.sim_to_render - 0.05).abs() < 1E-6);
+        assert_eq!(controller.take_steps(0.1, 5), 0);
+        assert!((controller.sim_to_render - 0.15).abs() < 1E-6);
+    }
+
+    #[test]
+    fn take_steps_caps_and_drops_time() {
+        let mut controller = controller_with_dt(0.25);
+        assert_eq!(controller.take_steps(2.1, 3), 3);
+        assert!((controller.sim_to_render - 0.1).abs() < 1E-6);
+        assert_eq!(controller.take_steps(0.2, 3), 1);
+    }
+
+    #[test]
+    fn take_steps_without_dt() {
+        let mut controller = controller_with_dt(0.0);
+        assert_eq!(controller.take_steps(1.0, 5), 0);
+        assert!(controller.sim_to_render.abs() < 1E-6);
+    }
+
+    #[test]
+    fn set_tuning_copies_tuning_and_keeps_state() {
+        let mut tuning = CharacterController {
+            walk_speed: 1.0,
+            jump_release: JumpRelease::ScaleGravity(2.0),
+            air_jump_speeds: vec![4.0, 3.0],
+            max_step_height: 0.5,
+            gravity: Some(-Vec3::X),
+            dt: 0.01,
+            velocity: Vec3::ONE,
+            jumping: true,
+            ..Default::default()
+        };
+        let mut controller = CharacterController {
+            fly: true,
+            velocity: Vec3::new(1.0, 2.0, 3.0),
+            air_jumps_used: 1,
+            takeoff_speed: 4.0,
+            crouching: true,
+            steps_this_frame: 2,
+            ..Default::default()
+        };
+        controller.input_state.jump_held = true;
+        tuning
+            .input_map
+            .bind(Action::Jump, Binding::Key(KeyCode::J));
+        controller.set_tuning(&tuning);
+
+        // The input map is kept
+        assert_eq!(
+            controller.input_map.bindings(Action::Jump),
+            InputMap::default().bindings(Action::Jump)
+        );
+        // Only the tuning and the input map are serialized
+        let mut tuned = controller.clone();
+        tuned.input_map = tuning.input_map.clone();
+        assert_eq!(
+            ron::ser::to_string(&tuned).unwrap(),
+            ron::ser::to_string(&tuning).unwrap()
+        );
+        assert!(controller.fly);
+        assert_eq!(controller.velocity, Vec3::new(1.0, 2.0, 3.0));
+        assert!(!controller.jumping);
+        assert_eq!(controller.air_jumps_used, 1);
+        assert!(controller.crouching);
+        assert_eq!(controller.steps_this_frame, 2);
+        assert!(controller.input_state.jump_held);
+    }
+
+    #[test]
+    fn ground_axes_follow_sideways_gravity() {
+        let gravity = Gravity(-9.81 * Vec3::X);
+        let up = CharacterController::default().up(&gravity);
+        assert!((up - Vec3::X).length() < 1E-6);
+        for &pitch in &[0.0, 0.7, -FRAC_PI_2, FRAC_PI_2] {
+            let look = LookDirection::from(look_rotation(Vec3::new(0.3, pitch, 0.0), up));
+            let (forward, right) = ground_axes(&look, up);
+            assert!(forward.is_finite() && right.is_finite());
+            assert!((forward.length() - 1.0).abs() < 1E-5);
+            assert!((right.length() - 1.0).abs() < 1E-5);
+            assert!(forward.dot(up).abs() < 1E-5);
+            assert!(right.dot(up).abs() < 1E-5);
+            assert!((right - look.right).length() < 1E-5);
+        }
+    }
+
+    #[test]
+    fn ground_axes_looking_along_up() {
+        let up = Vec3::new(1.0, 1.0, 0.0).normalize();
+        let look = LookDirection {
+            forward: up,
+            right: -up,
+            up: -Vec3::Z,
+        };
+        let (forward, right) = ground_axes(&look, up);
+        assert!(forward.is_finite() && right.is_finite());
+        assert!((forward.length() - 1.0).abs() < 1E-5);
+        assert!(forward.dot(up).abs() < 1E-5);
+        assert!(right.dot(up).abs() < 1E-5);
+    }
+}
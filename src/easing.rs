@@ -0,0 +1,110 @@
+/*
+ * Easing curves
+ *
+ * Shape how the character speeds up and slows down. A Curve covers a change
+ * of speed over a duration, with an Easing function giving the progress of
+ * the change over time.
+ */
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Easing {
+    Linear,
+    QuadraticIn,
+    QuadraticOut,
+    QuadraticInOut,
+    CubicIn,
+    CubicOut,
+    CubicInOut,
+}
+
+impl Easing {
+    /// Map a time in [0, 1] to a progress in [0, 1]
+    pub fn apply(&self, t: f32) -> f32 {
+        let t = t.max(0.0).min(1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::QuadraticIn => t * t,
+            Easing::QuadraticOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Easing::QuadraticInOut => {
+                if t < 0.5 {
+                    2.0 * t * t
+                } else {
+                    1.0 - 2.0 * (1.0 - t) * (1.0 - t)
+                }
+            }
+            Easing::CubicIn => t * t * t,
+            Easing::CubicOut => 1.0 - (1.0 - t).powi(3),
+            Easing::CubicInOut => {
+                if t < 0.5 {
+                    4.0 * t * t * t
+                } else {
+                    1.0 - 4.0 * (1.0 - t).powi(3)
+                }
+            }
+        }
+    }
+
+    /// The time in [0, 1] at which the curve reaches `progress`
+    pub fn inverse(&self, progress: f32) -> f32 {
+        // All of the curves are increasing, so bisect
+        let progress = progress.max(0.0).min(1.0);
+        let (mut low, mut high) = (0.0, 1.0);
+        for _ in 0..INVERSE_ITERATIONS {
+            let mid = 0.5 * (low + high);
+            if self.apply(mid) < progress {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        0.5 * (low + high)
+    }
+}
+
+const INVERSE_ITERATIONS: usize = 16;
+
+/// A change of speed that takes `duration` seconds, eased by `easing`
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Curve {
+    pub duration: f32,
+    pub easing: Easing,
+}
+
+impl Curve {
+    pub fn new(duration: f32, easing: Easing) -> Self {
+        Self { duration, easing }
+    }
+
+    /// A curve that changes speed immediately
+    pub fn instant() -> Self {
+        Self::new(0.0, Easing::Linear)
+    }
+
+    /// Change `speed` towards `target` for `dt` seconds. Speeding up follows
+    /// the curve from rest to `target`, and slowing down follows it from
+    /// `reference`, at least `speed`, to rest.
+    pub fn step(&self, speed: f32, target: f32, reference: f32, dt: f32) -> f32 {
+        if self.duration <= 0.0 {
+            return target;
+        }
+        let advance = dt / self.duration;
+        if speed < target {
+            if target <= 0.0 {
+                return target;
+            }
+            let progress = self.easing.inverse(speed.max(0.0) / target);
+            let next = self.easing.apply(progress + advance);
+            (target * next).max(speed).min(target)
+        } else {
+            let reference = reference.max(speed);
+            if reference <= 0.0 {
+                return target;
+            }
+            let progress = self.easing.inverse(1.0 - speed / reference);
+            let next = self.easing.apply(progress + advance);
+            (reference * (1.0 - next)).min(speed).max(target)
+        }
+    }
+}
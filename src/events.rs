@@ -1,17 +1,24 @@
+use crate::input_map::{Action, Binding};
 use bevy::prelude::*;
 use std::ops::Deref;
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct LookDeltaEvent {
+    entity: Entity,
     rotation_delta: Vec3,
 }
 
 impl LookDeltaEvent {
-    pub fn new(other: &Vec3) -> Self {
+    pub fn new(entity: Entity, other: &Vec3) -> Self {
         Self {
+            entity,
             rotation_delta: *other,
         }
     }
+
+    pub fn entity(&self) -> Entity {
+        self.entity
+    }
 }
 
 impl Deref for LookDeltaEvent {
@@ -22,14 +29,22 @@ impl Deref for LookDeltaEvent {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct LookEvent {
+    entity: Entity,
     rotation: Vec3,
 }
 
 impl LookEvent {
-    pub fn new(other: &Vec3) -> Self {
-        Self { rotation: *other }
+    pub fn new(entity: Entity, other: &Vec3) -> Self {
+        Self {
+            entity,
+            rotation: *other,
+        }
+    }
+
+    pub fn entity(&self) -> Entity {
+        self.entity
     }
 }
 
@@ -41,14 +56,22 @@ impl Deref for LookEvent {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct PitchEvent {
+    entity: Entity,
     pitch: f32,
 }
 
 impl PitchEvent {
-    pub fn new(value: f32) -> Self {
-        Self { pitch: value }
+    pub fn new(entity: Entity, value: f32) -> Self {
+        Self {
+            entity,
+            pitch: value,
+        }
+    }
+
+    pub fn entity(&self) -> Entity {
+        self.entity
     }
 }
 
@@ -60,14 +83,19 @@ impl Deref for PitchEvent {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct YawEvent {
+    entity: Entity,
     yaw: f32,
 }
 
 impl YawEvent {
-    pub fn new(value: f32) -> Self {
-        Self { yaw: value }
+    pub fn new(entity: Entity, value: f32) -> Self {
+        Self { entity, yaw: value }
+    }
+
+    pub fn entity(&self) -> Entity {
+        self.entity
     }
 }
 
@@ -79,17 +107,23 @@ impl Deref for YawEvent {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct TranslationEvent {
+    entity: Entity,
     translation: Vec3,
 }
 
 impl TranslationEvent {
-    pub fn new(other: &Vec3) -> Self {
+    pub fn new(entity: Entity, other: &Vec3) -> Self {
         Self {
+            entity,
             translation: *other,
         }
     }
+
+    pub fn entity(&self) -> Entity {
+        self.entity
+    }
 }
 
 impl Deref for TranslationEvent {
@@ -100,14 +134,22 @@ impl Deref for TranslationEvent {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct ImpulseEvent {
+    entity: Entity,
     impulse: Vec3,
 }
 
 impl ImpulseEvent {
-    pub fn new(other: &Vec3) -> Self {
-        Self { impulse: *other }
+    pub fn new(entity: Entity, other: &Vec3) -> Self {
+        Self {
+            entity,
+            impulse: *other,
+        }
+    }
+
+    pub fn entity(&self) -> Entity {
+        self.entity
     }
 }
 
@@ -119,14 +161,22 @@ impl Deref for ImpulseEvent {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct ForceEvent {
+    entity: Entity,
     force: Vec3,
 }
 
 impl ForceEvent {
-    pub fn new(other: &Vec3) -> Self {
-        Self { force: *other }
+    pub fn new(entity: Entity, other: &Vec3) -> Self {
+        Self {
+            entity,
+            force: *other,
+        }
+    }
+
+    pub fn entity(&self) -> Entity {
+        self.entity
     }
 }
 
@@ -137,3 +187,138 @@ impl Deref for ForceEvent {
         &self.force
     }
 }
+
+/// Sent when a character jumps. `air_jump` is 0 for a jump from the ground and
+/// counts up from 1 for each jump made in the air before landing.
+#[derive(Debug)]
+pub struct JumpEvent {
+    entity: Entity,
+    air_jump: usize,
+    speed: f32,
+}
+
+impl JumpEvent {
+    pub fn new(entity: Entity, air_jump: usize, speed: f32) -> Self {
+        Self {
+            entity,
+            air_jump,
+            speed,
+        }
+    }
+
+    pub fn entity(&self) -> Entity {
+        self.entity
+    }
+
+    pub fn air_jump(&self) -> usize {
+        self.air_jump
+    }
+}
+
+impl Deref for JumpEvent {
+    type Target = f32;
+
+    fn deref(&self) -> &Self::Target {
+        &self.speed
+    }
+}
+
+/// Sent when a character steps up onto a ledge, or down a step if the height
+/// is negative
+#[derive(Debug)]
+pub struct StepEvent {
+    entity: Entity,
+    height: f32,
+}
+
+impl StepEvent {
+    pub fn new(entity: Entity, height: f32) -> Self {
+        Self { entity, height }
+    }
+
+    pub fn entity(&self) -> Entity {
+        self.entity
+    }
+}
+
+impl Deref for StepEvent {
+    type Target = f32;
+
+    fn deref(&self) -> &Self::Target {
+        &self.height
+    }
+}
+
+/// Sent with the new bindings of an action whenever they are changed by rebinding
+#[derive(Debug)]
+pub struct BindingChanged {
+    entity: Entity,
+    action: Action,
+    bindings: Vec<Binding>,
+}
+
+impl BindingChanged {
+    pub fn new(entity: Entity, action: Action, bindings: &[Binding]) -> Self {
+        Self {
+            entity,
+            action,
+            bindings: bindings.to_vec(),
+        }
+    }
+
+    pub fn entity(&self) -> Entity {
+        self.entity
+    }
+
+    pub fn action(&self) -> Action {
+        self.action
+    }
+}
+
+impl Deref for BindingChanged {
+    type Target = [Binding];
+
+    fn deref(&self) -> &Self::Target {
+        &self.bindings
+    }
+}
+
+/// Sent when a captured binding is rejected because other actions already use it
+#[derive(Debug)]
+pub struct BindingConflict {
+    entity: Entity,
+    action: Action,
+    binding: Binding,
+    conflicts: Vec<Action>,
+}
+
+impl BindingConflict {
+    pub fn new(entity: Entity, action: Action, binding: &Binding, conflicts: &[Action]) -> Self {
+        Self {
+            entity,
+            action,
+            binding: binding.clone(),
+            conflicts: conflicts.to_vec(),
+        }
+    }
+
+    pub fn entity(&self) -> Entity {
+        self.entity
+    }
+
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    pub fn binding(&self) -> &Binding {
+        &self.binding
+    }
+}
+
+impl Deref for BindingConflict {
+    type Target = [Action];
+
+    fn deref(&self) -> &Self::Target {
+        &self.conflicts
+    }
+}
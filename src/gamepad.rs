@@ -0,0 +1,96 @@
+/*
+ * Gamepad input
+ *
+ * Assign a gamepad to a character by adding a CharacterGamepad to its body.
+ * Gamepad buttons and sticks are then bound to actions in the character's
+ * InputMap.
+ */
+
+use bevy::prelude::*;
+
+/// Shapes stick deflection once the dead zone has been removed, so that small
+/// deflections can be made less sensitive than large ones
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResponseCurve {
+    Linear,
+    Quadratic,
+    Cubic,
+    Power(f32),
+}
+
+impl ResponseCurve {
+    /// Map a deflection in [0, 1] to [0, 1]
+    pub fn apply(&self, value: f32) -> f32 {
+        match self {
+            ResponseCurve::Linear => value,
+            ResponseCurve::Quadratic => value * value,
+            ResponseCurve::Cubic => value * value * value,
+            ResponseCurve::Power(exponent) => value.powf(*exponent),
+        }
+    }
+}
+
+/// The gamepad used to control a character, with its stick settings
+#[derive(Clone, Debug)]
+pub struct CharacterGamepad {
+    pub gamepad: Gamepad,
+    pub left_stick_dead_zone: f32,
+    pub left_stick_curve: ResponseCurve,
+    pub right_stick_dead_zone: f32,
+    pub right_stick_curve: ResponseCurve,
+}
+
+impl CharacterGamepad {
+    pub fn new(gamepad: Gamepad) -> Self {
+        Self {
+            gamepad,
+            left_stick_dead_zone: 0.15,
+            left_stick_curve: ResponseCurve::Linear,
+            right_stick_dead_zone: 0.1,
+            right_stick_curve: ResponseCurve::Quadratic,
+        }
+    }
+
+    /// Left stick deflection, x to the right and y up
+    pub fn left_stick(&self, axes: &Axis<GamepadAxis>) -> Vec2 {
+        self.stick(
+            axes,
+            GamepadAxisType::LeftStickX,
+            GamepadAxisType::LeftStickY,
+            self.left_stick_dead_zone,
+            self.left_stick_curve,
+        )
+    }
+
+    /// Right stick deflection, x to the right and y up
+    pub fn right_stick(&self, axes: &Axis<GamepadAxis>) -> Vec2 {
+        self.stick(
+            axes,
+            GamepadAxisType::RightStickX,
+            GamepadAxisType::RightStickY,
+            self.right_stick_dead_zone,
+            self.right_stick_curve,
+        )
+    }
+
+    fn stick(
+        &self,
+        axes: &Axis<GamepadAxis>,
+        x: GamepadAxisType,
+        y: GamepadAxisType,
+        dead_zone: f32,
+        curve: ResponseCurve,
+    ) -> Vec2 {
+        let stick = Vec2::new(
+            axes.get(GamepadAxis(self.gamepad, x)).unwrap_or(0.0),
+            axes.get(GamepadAxis(self.gamepad, y)).unwrap_or(0.0),
+        );
+        // Radial dead zone, rescaled so that the output starts from zero at its edge
+        let magnitude = stick.length().min(1.0);
+        if magnitude <= dead_zone {
+            return Vec2::ZERO;
+        }
+        let scaled = (magnitude - dead_zone) / (1.0 - dead_zone);
+        stick.normalize() * curve.apply(scaled)
+    }
+}
@@ -1,34 +1,474 @@
-use bevy::input::keyboard::KeyCode;
+/*
+ * Action-based input
+ *
+ * Each character's InputMap binds actions to any number of keys, mouse
+ * buttons, mouse wheel directions, gamepad buttons and sticks. Every frame
+ * the bindings are evaluated into the character's ActionState, which the
+ * controller systems read instead of the raw input.
+ */
 
-#[derive(Debug)]
+use crate::{
+    controller::CharacterController,
+    gamepad::CharacterGamepad,
+    look::{LookSettings, MouseLookTag},
+    rebind::ListeningForBinding,
+};
+use bevy::{
+    input::mouse::{MouseMotion, MouseWheel},
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Action {
+    /// Axis with x to the right and y forward
+    Move,
+    /// Axis with x to the right and y up, in radians for this frame
+    Look,
+    Jump,
+    Run,
+    Crouch,
+    ToggleFly,
+    FlyUp,
+    FlyDown,
+}
+
+impl Action {
+    pub const ALL: [Action; 8] = [
+        Action::Move,
+        Action::Look,
+        Action::Jump,
+        Action::Run,
+        Action::Crouch,
+        Action::ToggleFly,
+        Action::FlyUp,
+        Action::FlyDown,
+    ];
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Binding {
+    Key(KeyCode),
+    MouseButton(MouseButton),
+    MouseWheelUp,
+    MouseWheelDown,
+    /// A button on the character's `CharacterGamepad`
+    GamepadButton(GamepadButtonType),
+    /// Active while all of the bindings are held
+    Chord(Vec<Binding>),
+    /// Four bindings that push an axis up, down, left and right
+    Directional {
+        up: Box<Binding>,
+        down: Box<Binding>,
+        left: Box<Binding>,
+        right: Box<Binding>,
+    },
+    MouseMotion,
+    GamepadLeftStick,
+    GamepadRightStick,
+}
+
+impl Binding {
+    pub fn directional(up: KeyCode, down: KeyCode, left: KeyCode, right: KeyCode) -> Self {
+        Binding::Directional {
+            up: Box::new(Binding::Key(up)),
+            down: Box::new(Binding::Key(down)),
+            left: Box::new(Binding::Key(left)),
+            right: Box::new(Binding::Key(right)),
+        }
+    }
+
+    /// Whether the binding gives a continuous value, rather than on / off
+    pub fn is_analog(&self) -> bool {
+        matches!(self, Binding::GamepadLeftStick | Binding::GamepadRightStick)
+    }
+
+    /// The single keys, buttons and axes that the binding is made of
+    pub fn inputs(&self) -> Vec<&Binding> {
+        match self {
+            Binding::Chord(bindings) => bindings.iter().flat_map(Binding::inputs).collect(),
+            Binding::Directional {
+                up,
+                down,
+                left,
+                right,
+            } => vec![up, down, left, right]
+                .into_iter()
+                .flat_map(|binding| binding.inputs())
+                .collect(),
+            _ => vec![self],
+        }
+    }
+
+    /// Whether the two bindings share any input
+    pub fn overlaps(&self, other: &Binding) -> bool {
+        let other_inputs = other.inputs();
+        self.inputs()
+            .iter()
+            .any(|input| other_inputs.contains(input))
+    }
+
+    fn pressed(&self, input: &RawInput) -> bool {
+        match self {
+            Binding::Key(key) => input.keyboard.pressed(*key),
+            Binding::MouseButton(button) => input.mouse_buttons.pressed(*button),
+            Binding::MouseWheelUp => input.mouse_wheel > 0.0,
+            Binding::MouseWheelDown => input.mouse_wheel < 0.0,
+            Binding::GamepadButton(button) => input.gamepad.map_or(false, |gamepad| {
+                input
+                    .gamepad_buttons
+                    .pressed(GamepadButton(gamepad.gamepad, *button))
+            }),
+            Binding::Chord(bindings) => {
+                !bindings.is_empty() && bindings.iter().all(|binding| binding.pressed(input))
+            }
+            _ => self.axis(input) != Vec2::ZERO,
+        }
+    }
+
+    fn just_pressed(&self, input: &RawInput) -> bool {
+        match self {
+            Binding::Key(key) => input.keyboard.just_pressed(*key),
+            Binding::MouseButton(button) => input.mouse_buttons.just_pressed(*button),
+            Binding::MouseWheelUp | Binding::MouseWheelDown => self.pressed(input),
+            Binding::GamepadButton(button) => input.gamepad.map_or(false, |gamepad| {
+                input
+                    .gamepad_buttons
+                    .just_pressed(GamepadButton(gamepad.gamepad, *button))
+            }),
+            // A chord is pressed once the last of its bindings is pressed
+            Binding::Chord(bindings) => {
+                self.pressed(input) && bindings.iter().any(|binding| binding.just_pressed(input))
+            }
+            _ => false,
+        }
+    }
+
+    /// Axis value with x to the right and y up
+    fn axis(&self, input: &RawInput) -> Vec2 {
+        let value = |binding: &Binding| if binding.pressed(input) { 1.0 } else { 0.0 };
+        match self {
+            Binding::Directional {
+                up,
+                down,
+                left,
+                right,
+            } => Vec2::new(value(right) - value(left), value(up) - value(down)),
+            Binding::MouseMotion => Vec2::new(input.mouse_motion.x, -input.mouse_motion.y),
+            Binding::GamepadLeftStick => input
+                .gamepad
+                .map_or(Vec2::ZERO, |gamepad| gamepad.left_stick(input.gamepad_axes)),
+            Binding::GamepadRightStick => input.gamepad.map_or(Vec2::ZERO, |gamepad| {
+                gamepad.right_stick(input.gamepad_axes)
+            }),
+            _ => Vec2::ZERO,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct InputMap {
-    pub key_forward: KeyCode,
-    pub key_backward: KeyCode,
-    pub key_left: KeyCode,
-    pub key_right: KeyCode,
-    pub key_jump: KeyCode,
-    pub key_run: KeyCode,
-    pub key_crouch: KeyCode,
-    pub invert_y: bool,
-    pub key_fly: KeyCode,
-    pub key_fly_up: KeyCode,
-    pub key_fly_down: KeyCode,
+    bindings: HashMap<Action, Vec<Binding>>,
 }
 
 impl Default for InputMap {
     fn default() -> Self {
+        let mut input_map = Self::empty();
+        input_map
+            .bind(
+                Action::Move,
+                Binding::directional(KeyCode::W, KeyCode::S, KeyCode::A, KeyCode::D),
+            )
+            .bind(Action::Move, Binding::GamepadLeftStick)
+            .bind(Action::Look, Binding::MouseMotion)
+            .bind(Action::Look, Binding::GamepadRightStick)
+            .bind(Action::Jump, Binding::Key(KeyCode::Space))
+            .bind(
+                Action::Jump,
+                Binding::GamepadButton(GamepadButtonType::South),
+            )
+            .bind(Action::Run, Binding::Key(KeyCode::LShift))
+            .bind(
+                Action::Run,
+                Binding::GamepadButton(GamepadButtonType::LeftThumb),
+            )
+            .bind(Action::Crouch, Binding::Key(KeyCode::LControl))
+            .bind(
+                Action::Crouch,
+                Binding::GamepadButton(GamepadButtonType::East),
+            )
+            .bind(Action::ToggleFly, Binding::Key(KeyCode::F))
+            .bind(
+                Action::ToggleFly,
+                Binding::GamepadButton(GamepadButtonType::North),
+            )
+            .bind(Action::FlyUp, Binding::Key(KeyCode::E))
+            .bind(
+                Action::FlyUp,
+                Binding::GamepadButton(GamepadButtonType::RightTrigger),
+            )
+            .bind(Action::FlyDown, Binding::Key(KeyCode::Q))
+            .bind(
+                Action::FlyDown,
+                Binding::GamepadButton(GamepadButtonType::LeftTrigger),
+            );
+        input_map
+    }
+}
+
+impl InputMap {
+    /// An input map without any bindings
+    pub fn empty() -> Self {
         Self {
-            key_forward: KeyCode::W,
-            key_backward: KeyCode::S,
-            key_left: KeyCode::A,
-            key_right: KeyCode::D,
-            key_jump: KeyCode::Space,
-            key_run: KeyCode::LShift,
-            key_crouch: KeyCode::LControl,
-            invert_y: false,
-            key_fly: KeyCode::F,
-            key_fly_up: KeyCode::E,
-            key_fly_down: KeyCode::Q,
+            bindings: HashMap::default(),
+        }
+    }
+
+    /// Add a binding to an action, in addition to its existing bindings
+    pub fn bind(&mut self, action: Action, binding: Binding) -> &mut Self {
+        let bindings = self.bindings.entry(action).or_insert_with(Vec::new);
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+        self
+    }
+
+    pub fn unbind(&mut self, action: Action, binding: &Binding) -> &mut Self {
+        if let Some(bindings) = self.bindings.get_mut(&action) {
+            bindings.retain(|other| other != binding);
+        }
+        self
+    }
+
+    /// Remove the bindings of `action` that share any input with `binding`
+    pub fn unbind_overlapping(&mut self, action: Action, binding: &Binding) -> &mut Self {
+        if let Some(bindings) = self.bindings.get_mut(&action) {
+            bindings.retain(|other| !other.overlaps(binding));
         }
+        self
+    }
+
+    pub fn clear(&mut self, action: Action) -> &mut Self {
+        self.bindings.remove(&action);
+        self
+    }
+
+    /// Restore the default bindings of an action
+    pub fn reset(&mut self, action: Action) -> &mut Self {
+        match InputMap::default().bindings.remove(&action) {
+            Some(bindings) => self.bindings.insert(action, bindings),
+            None => self.bindings.remove(&action),
+        };
+        self
+    }
+
+    pub fn bindings(&self, action: Action) -> &[Binding] {
+        self.bindings.get(&action).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Actions other than `action` with a binding that shares any input with
+    /// `binding`, including the keys of chords and directional bindings
+    pub fn conflicts(&self, action: Action, binding: &Binding) -> Vec<Action> {
+        let mut conflicts: Vec<Action> = Vec::new();
+        for (other, other_binding) in self.iter() {
+            if other != action && other_binding.overlaps(binding) && !conflicts.contains(&other) {
+                conflicts.push(other);
+            }
+        }
+        conflicts
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        Ok(ron::de::from_bytes(&fs::read(path)?)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), anyhow::Error> {
+        let ron = ron::ser::to_string_pretty(self, PrettyConfig::default())?;
+        fs::write(path, ron)?;
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Action, &Binding)> {
+        self.bindings
+            .iter()
+            .flat_map(|(action, bindings)| bindings.iter().map(move |binding| (*action, binding)))
+    }
+}
+
+/// The state of each action of a character for the current frame
+#[derive(Debug, Default)]
+pub struct ActionState {
+    pressed: HashSet<Action>,
+    just_pressed: HashSet<Action>,
+    axes: HashMap<Action, Vec2>,
+    analog: HashSet<Action>,
+}
+
+impl ActionState {
+    pub fn pressed(&self, action: Action) -> bool {
+        self.pressed.contains(&action)
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.just_pressed.contains(&action)
+    }
+
+    pub fn axis(&self, action: Action) -> Vec2 {
+        self.axes.get(&action).copied().unwrap_or(Vec2::ZERO)
+    }
+
+    /// Whether an analog binding, such as a gamepad stick, contributed to the
+    /// action's axis
+    pub fn analog(&self, action: Action) -> bool {
+        self.analog.contains(&action)
+    }
+
+    fn clear(&mut self) {
+        self.pressed.clear();
+        self.just_pressed.clear();
+        self.axes.clear();
+        self.analog.clear();
+    }
+}
+
+/// The input of the current frame that bindings are evaluated against
+struct RawInput<'a> {
+    keyboard: &'a Input<KeyCode>,
+    mouse_buttons: &'a Input<MouseButton>,
+    mouse_motion: Vec2,
+    mouse_wheel: f32,
+    gamepad_buttons: &'a Input<GamepadButton>,
+    gamepad_axes: &'a Axis<GamepadAxis>,
+    gamepad: Option<&'a CharacterGamepad>,
+}
+
+pub fn create_action_state(
+    mut commands: Commands,
+    query: Query<Entity, (With<CharacterController>, Without<ActionState>)>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).insert(ActionState::default());
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn input_to_actions(
+    time: Res<Time>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_button_input: Res<Input<MouseButton>>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    gamepad_axes: Res<Axis<GamepadAxis>>,
+    mut mouse_motion_events: EventReader<MouseMotion>,
+    mut mouse_wheel_events: EventReader<MouseWheel>,
+    mut query: Query<(
+        &CharacterController,
+        &mut ActionState,
+        Option<&LookSettings>,
+        Option<&MouseLookTag>,
+        Option<&CharacterGamepad>,
+        Option<&ListeningForBinding>,
+    )>,
+) {
+    let mut mouse_motion = Vec2::ZERO;
+    for motion in mouse_motion_events.iter() {
+        mouse_motion += motion.delta;
+    }
+    let mut mouse_wheel = 0.0;
+    for wheel in mouse_wheel_events.iter() {
+        mouse_wheel += wheel.y;
+    }
+
+    for (controller, mut action_state, look_settings, mouse_look, gamepad, listening) in
+        query.iter_mut()
+    {
+        action_state.clear();
+        // Input that is about to be bound should not also trigger actions
+        if listening.is_some() {
+            continue;
+        }
+        let input = RawInput {
+            keyboard: &keyboard_input,
+            mouse_buttons: &mouse_button_input,
+            // Only characters looked around with the local mouse follow it
+            mouse_motion: if mouse_look.is_some() {
+                mouse_motion
+            } else {
+                Vec2::ZERO
+            },
+            mouse_wheel,
+            gamepad_buttons: &gamepad_buttons,
+            gamepad_axes: &gamepad_axes,
+            gamepad,
+        };
+        let look_settings = look_settings.copied().unwrap_or_default();
+
+        for (action, binding) in controller.input_map.iter() {
+            if binding.pressed(&input) {
+                action_state.pressed.insert(action);
+            }
+            if binding.just_pressed(&input) {
+                action_state.just_pressed.insert(action);
+            }
+            let mut value = binding.axis(&input);
+            if value == Vec2::ZERO {
+                continue;
+            }
+            if action == Action::Look {
+                // Mouse motion is a distance, other bindings turn at a rate
+                value *= if *binding == Binding::MouseMotion {
+                    Vec2::new(
+                        look_settings.horizontal_sensitivity,
+                        look_settings.vertical_sensitivity,
+                    )
+                } else {
+                    look_settings.look_rate * time.delta_seconds()
+                };
+            }
+            *action_state.axes.entry(action).or_insert(Vec2::ZERO) += value;
+            if binding.is_analog() {
+                action_state.analog.insert(action);
+            }
+        }
+
+        if let Some(movement) = action_state.axes.get_mut(&Action::Move) {
+            if movement.length_squared() > 1.0 {
+                *movement = movement.normalize();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conflicts_with_keys_inside_composite_bindings() {
+        let input_map = InputMap::default();
+        assert_eq!(
+            input_map.conflicts(Action::Jump, &Binding::Key(KeyCode::W)),
+            vec![Action::Move]
+        );
+        let chord = Binding::Chord(vec![Binding::Key(KeyCode::LAlt), Binding::Key(KeyCode::F)]);
+        assert_eq!(
+            input_map.conflicts(Action::Jump, &chord),
+            vec![Action::ToggleFly]
+        );
+        assert!(input_map
+            .conflicts(Action::Move, &Binding::Key(KeyCode::W))
+            .is_empty());
+    }
+
+    #[test]
+    fn unbind_overlapping_removes_composite_bindings() {
+        let mut input_map = InputMap::default();
+        input_map.unbind_overlapping(Action::Move, &Binding::Key(KeyCode::W));
+        assert_eq!(
+            input_map.bindings(Action::Move),
+            &[Binding::GamepadLeftStick]
+        );
     }
 }
@@ -0,0 +1,123 @@
+/*
+ * Render interpolation
+ *
+ * Characters only move on simulation steps, so when the frame rate does not
+ * match the steps their motion stutters. Add the RenderInterpolationPlugin and
+ * a RenderInterpolation component to a kinematic character body to render it
+ * between the last two simulated translations instead. Children such as the
+ * head and camera follow the interpolated translation. Rotation follows look
+ * input every frame and is not interpolated.
+ *
+ * The simulated translation is put back at the start of the next frame, unless
+ * something else has moved the body since it was interpolated, such as a
+ * teleport or a backend that writes the Transform from its physics state. That
+ * translation is then kept and rendered without interpolation.
+ */
+
+use crate::{
+    backend::BODY_TO_GROUND_STATE_SYSTEM,
+    controller::{CharacterController, ACTIONS_TO_INPUT_STATE_SYSTEM},
+};
+use bevy::{prelude::*, transform::TransformSystem};
+
+pub const CREATE_SIMULATED_TRANSLATION_SYSTEM: &str = "create_simulated_translation";
+pub const RESTORE_SIMULATED_TRANSLATION_SYSTEM: &str = "restore_simulated_translation";
+pub const INTERPOLATE_TRANSLATION_SYSTEM: &str = "interpolate_translation";
+
+/// Render this character body's translation between simulation steps
+pub struct RenderInterpolation;
+
+/// The simulated translations that the rendered translation is between
+#[derive(Debug)]
+pub struct SimulatedTranslation {
+    pub previous: Vec3,
+    pub current: Vec3,
+    /// The translation last rendered, to tell whether anything else has moved
+    /// the body since
+    pub rendered: Vec3,
+}
+
+pub struct RenderInterpolationPlugin;
+
+impl Plugin for RenderInterpolationPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_system(
+            create_simulated_translation
+                .system()
+                .label(CREATE_SIMULATED_TRANSLATION_SYSTEM),
+        )
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            restore_simulated_translation
+                .system()
+                .label(RESTORE_SIMULATED_TRANSLATION_SYSTEM)
+                .before(BODY_TO_GROUND_STATE_SYSTEM)
+                .before(ACTIONS_TO_INPUT_STATE_SYSTEM),
+        )
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            interpolate_translation
+                .system()
+                .label(INTERPOLATE_TRANSLATION_SYSTEM)
+                .before(TransformSystem::TransformPropagate),
+        );
+    }
+}
+
+pub fn create_simulated_translation(
+    mut commands: Commands,
+    query: Query<(Entity, &Transform), (With<RenderInterpolation>, Without<SimulatedTranslation>)>,
+) {
+    for (entity, transform) in query.iter() {
+        commands.entity(entity).insert(SimulatedTranslation {
+            previous: transform.translation,
+            current: transform.translation,
+            rendered: transform.translation,
+        });
+    }
+}
+
+/// Put back the simulated translation before the simulation reads it, or keep
+/// the translation if something else has moved the body since it was rendered
+pub fn restore_simulated_translation(
+    mut query: Query<(&mut Transform, &mut SimulatedTranslation), With<RenderInterpolation>>,
+) {
+    for (mut transform, mut simulated) in query.iter_mut() {
+        if transform.translation == simulated.rendered {
+            transform.translation = simulated.current;
+        } else if transform.translation != simulated.current {
+            simulated.previous = transform.translation;
+            simulated.current = transform.translation;
+        }
+    }
+}
+
+/// Remember the translations before and after the last simulation step of
+/// each frame that took steps, and render the translation between them by the
+/// controller's interpolation alpha
+pub fn interpolate_translation(
+    mut query: Query<
+        (
+            &mut Transform,
+            &mut SimulatedTranslation,
+            &CharacterController,
+        ),
+        With<RenderInterpolation>,
+    >,
+) {
+    for (mut transform, mut simulated, controller) in query.iter_mut() {
+        if controller.steps_this_frame > 0 {
+            // The moves of all the steps in a frame are applied together, so
+            // the translation before the last step is that far along the way
+            let steps = controller.steps_this_frame as f32;
+            simulated.previous = simulated
+                .current
+                .lerp(transform.translation, (steps - 1.0) / steps);
+            simulated.current = transform.translation;
+        }
+        transform.translation = simulated
+            .previous
+            .lerp(simulated.current, controller.interpolation_alpha());
+        simulated.rendered = transform.translation;
+    }
+}
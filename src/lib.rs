@@ -1,8 +1,16 @@
+pub mod backend;
+pub mod camera;
 pub mod controller;
+pub mod easing;
 pub mod events;
+pub mod gamepad;
 pub mod input_map;
+pub mod interpolation;
 pub mod look;
 #[cfg(feature = "use_physx")]
 pub mod physx;
+pub mod preset;
 #[cfg(feature = "use_rapier")]
 pub mod rapier;
+pub mod rebind;
+pub mod simple;
@@ -1,6 +1,11 @@
 // system that converts delta axis events into pitch and yaw
-use crate::events::{LookDeltaEvent, LookEvent, PitchEvent, YawEvent};
-use bevy::{input::mouse::MouseMotion, prelude::*};
+use crate::{
+    controller::{CharacterController, Gravity, GroundState},
+    events::{LookDeltaEvent, LookEvent, PitchEvent, YawEvent},
+    input_map::{Action, ActionState},
+};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Copy)]
 pub struct LookDirection {
@@ -19,31 +24,107 @@ impl Default for LookDirection {
     }
 }
 
+impl From<Quat> for LookDirection {
+    fn from(rotation: Quat) -> Self {
+        Self {
+            forward: rotation * -Vec3::Z,
+            right: rotation * Vec3::X,
+            up: rotation * Vec3::Y,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct LookEntity(pub Entity);
 
-pub fn forward_up(settings: Res<MouseSettings>, mut query: Query<&mut LookDirection>) {
-    for mut look in query.iter_mut() {
-        let rotation = Quat::from_rotation_ypr(
-            settings.yaw_pitch_roll.x,
-            settings.yaw_pitch_roll.y,
-            settings.yaw_pitch_roll.z,
-        );
-        look.forward = rotation * -Vec3::Z;
-        look.right = rotation * Vec3::X;
-        look.up = rotation * Vec3::Y;
+/// Per-character look orientation, sensitivity and inversion, added to the body
+/// next to its `LookEntity`. Initialised from the `MouseSettings` resource if not
+/// present. The orientation is not serialized.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LookSettings {
+    pub horizontal_sensitivity: f32,
+    pub vertical_sensitivity: f32,
+    /// Yaw and pitch speed in radians per second for sticks and keys bound to look
+    pub look_rate: Vec2,
+    pub invert_x: bool,
+    pub invert_y: bool,
+    #[serde(skip)]
+    pub yaw_pitch_roll: Vec3,
+}
+
+impl Default for LookSettings {
+    fn default() -> Self {
+        Self::from(&MouseSettings::default())
+    }
+}
+
+impl From<&MouseSettings> for LookSettings {
+    fn from(settings: &MouseSettings) -> Self {
+        Self {
+            horizontal_sensitivity: settings.horizontal_sensitivity,
+            vertical_sensitivity: settings.vertical_sensitivity,
+            look_rate: settings.look_rate,
+            invert_x: settings.invert_x,
+            invert_y: settings.invert_y,
+            yaw_pitch_roll: settings.yaw_pitch_roll,
+        }
     }
 }
 
+/// Tag for characters that are looked around with the local mouse
+pub struct MouseLookTag;
+
+pub fn insert_look_settings(
+    mut commands: Commands,
+    settings: Res<MouseSettings>,
+    query: Query<Entity, (With<LookEntity>, Without<LookSettings>)>,
+) {
+    for entity in query.iter() {
+        commands
+            .entity(entity)
+            .insert(LookSettings::from(&*settings));
+    }
+}
+
+/// Rotation for `yaw_pitch_roll`, with the yaw turning about `up` instead of
+/// the world y axis
+pub fn look_rotation(yaw_pitch_roll: Vec3, up: Vec3) -> Quat {
+    Quat::from_rotation_arc(Vec3::Y, up)
+        * Quat::from_rotation_ypr(yaw_pitch_roll.x, yaw_pitch_roll.y, yaw_pitch_roll.z)
+}
+
+pub fn forward_up(
+    gravity: Res<Gravity>,
+    query: Query<(&LookSettings, &LookEntity, Option<&CharacterController>)>,
+    mut look_query: Query<&mut LookDirection>,
+) {
+    for (settings, look_entity, controller) in query.iter() {
+        if let Ok(mut look) = look_query.get_mut(look_entity.0) {
+            let up = controller.map_or_else(|| gravity.up(), |controller| controller.up(&gravity));
+            *look = LookDirection::from(look_rotation(settings.yaw_pitch_roll, up));
+        }
+    }
+}
+
+/// Default look settings used to initialise each character's `LookSettings`
 pub struct MouseSettings {
-    pub sensitivity: f32,
+    pub horizontal_sensitivity: f32,
+    pub vertical_sensitivity: f32,
+    pub look_rate: Vec2,
+    pub invert_x: bool,
+    pub invert_y: bool,
     pub yaw_pitch_roll: Vec3,
 }
 
 impl Default for MouseSettings {
     fn default() -> Self {
         Self {
-            sensitivity: 0.01,
+            horizontal_sensitivity: 0.01,
+            vertical_sensitivity: 0.01,
+            look_rate: Vec2::new(3.0, 2.0),
+            invert_x: false,
+            invert_y: false,
             yaw_pitch_roll: Vec3::ZERO,
         }
     }
@@ -52,20 +133,38 @@ impl Default for MouseSettings {
 const PITCH_BOUND: f32 = std::f32::consts::FRAC_PI_2 - 1E-3;
 
 pub fn input_to_look(
-    mut mouse_motion_events: EventReader<MouseMotion>,
-    mut settings: ResMut<MouseSettings>,
+    time: Res<Time>,
+    gravity: Res<Gravity>,
     mut pitch_events: EventWriter<PitchEvent>,
     mut yaw_events: EventWriter<YawEvent>,
     mut look_events: EventWriter<LookEvent>,
     mut look_delta_events: EventWriter<LookDeltaEvent>,
+    mut query: Query<(
+        Entity,
+        &mut LookSettings,
+        &ActionState,
+        Option<&GroundState>,
+        Option<&CharacterController>,
+    )>,
 ) {
-    let mut delta = Vec2::ZERO;
-    for motion in mouse_motion_events.iter() {
-        // NOTE: -= to invert
-        delta -= motion.delta;
-    }
-    if delta.length_squared() > 1E-6 {
-        delta *= settings.sensitivity;
+    for (entity, mut settings, action_state, ground_state, controller) in query.iter_mut() {
+        // Looking right yaws clockwise and looking up pitches up
+        let look = action_state.axis(Action::Look);
+        let mut delta = Vec2::new(-look.x, look.y);
+        if settings.invert_x {
+            delta.x = -delta.x;
+        }
+        if settings.invert_y {
+            delta.y = -delta.y;
+        }
+        // Turn with the platform the character is standing on
+        if let Some(ground) = ground_state.filter(|ground| ground.grounded) {
+            let up = controller.map_or_else(|| gravity.up(), |controller| controller.up(&gravity));
+            delta.x += ground.platform_angular_velocity.dot(up) * time.delta_seconds();
+        }
+        if delta == Vec2::ZERO {
+            continue;
+        }
         settings.yaw_pitch_roll += delta.extend(0.0);
         if settings.yaw_pitch_roll.y > PITCH_BOUND {
             settings.yaw_pitch_roll.y = PITCH_BOUND;
@@ -73,9 +172,22 @@ pub fn input_to_look(
         if settings.yaw_pitch_roll.y < -PITCH_BOUND {
             settings.yaw_pitch_roll.y = -PITCH_BOUND;
         }
-        look_delta_events.send(LookDeltaEvent::new(&delta.extend(0.0)));
-        look_events.send(LookEvent::new(&settings.yaw_pitch_roll));
-        pitch_events.send(PitchEvent::new(settings.yaw_pitch_roll.y));
-        yaw_events.send(YawEvent::new(settings.yaw_pitch_roll.x));
+        look_delta_events.send(LookDeltaEvent::new(entity, &delta.extend(0.0)));
+        look_events.send(LookEvent::new(entity, &settings.yaw_pitch_roll));
+        pitch_events.send(PitchEvent::new(entity, settings.yaw_pitch_roll.y));
+        yaw_events.send(YawEvent::new(entity, settings.yaw_pitch_roll.x));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn look_yaws_about_up() {
+        let up = -Vec3::Z;
+        let rotation = look_rotation(Vec3::new(1.2, 0.0, 0.0), up);
+        assert!((rotation * Vec3::Y - up).length() < 1E-5);
+        assert!((rotation * -Vec3::Z).dot(up).abs() < 1E-5);
     }
 }
@@ -1,16 +1,476 @@
-use crate::{controller::*, events::*};
-use bevy::{app::Events, prelude::*};
+use crate::{backend::*, controller::*};
+use bevy::prelude::*;
 use bevy_prototype_physx::*;
+use physx::controller::CollisionFlags;
+
+pub struct PhysXBackend;
+
+impl CharacterPhysicsBackend for PhysXBackend {
+    type Scene = PhysX;
+    type Body = (
+        &'static mut Transform,
+        Option<&'static PhysXDynamicRigidBodyHandle>,
+        Option<&'static mut PhysXController>,
+        Option<&'static PhysXColliderDesc>,
+        Option<&'static PhysXCapsuleControllerDesc>,
+        Option<&'static Crouched>,
+    );
+    type Colliders = (
+        Entity,
+        Option<&'static PhysXStaticRigidBodyHandle>,
+        Option<&'static PhysXDynamicRigidBodyHandle>,
+    );
+    type Platform = (
+        &'static PhysXDynamicRigidBodyHandle,
+        &'static GlobalTransform,
+    );
+
+    fn build(app: &mut AppBuilder) {
+        app.add_system(constrain_rotation.system())
+            .add_system(reject_dynamic_noclip.system());
+    }
+
+    fn mass(scene: &Self::Scene, body: &BodyItem<'_, Self>) -> Option<f32> {
+        body.1.map(|body_handle| {
+            scene
+                .scene
+                .get_dynamic(body_handle.0)
+                .expect("Failed to get dynamic rigid body")
+                .get_mass()
+        })
+    }
+
+    fn velocity(scene: &Self::Scene, body: &BodyItem<'_, Self>) -> Vec3 {
+        body.1.map_or(Vec3::ZERO, |body_handle| {
+            scene
+                .scene
+                .get_dynamic(body_handle.0)
+                .expect("Failed to get dynamic rigid body")
+                .get_linear_velocity()
+        })
+    }
+
+    fn apply_impulse(scene: &mut Self::Scene, body: &mut BodyItem<'_, Self>, impulse: Vec3) {
+        if let Some(body_handle) = body.1 {
+            let body = scene
+                .scene
+                .get_dynamic_mut(body_handle.0)
+                .expect("Failed to get dynamic rigid body");
+            body.add_force(impulse, physx::rigid_body::ForceMode::Impulse, true);
+        }
+    }
+
+    fn apply_force(scene: &mut Self::Scene, body: &mut BodyItem<'_, Self>, force: Vec3) {
+        if let Some(body_handle) = body.1 {
+            let body = scene
+                .scene
+                .get_dynamic_mut(body_handle.0)
+                .expect("Failed to get dynamic rigid body");
+            body.add_force(force, physx::rigid_body::ForceMode::Force, true);
+        }
+    }
+
+    fn apply_gravity(
+        scene: &mut Self::Scene,
+        body: &mut BodyItem<'_, Self>,
+        gravity: Vec3,
+        mass: f32,
+    ) {
+        // Make up the difference between the character's gravity and that of
+        // the scene, which still acts on the body
+        let difference = gravity - scene.scene.get_gravity();
+        if difference.length_squared() > 1E-6 {
+            Self::apply_force(scene, body, difference * mass);
+        }
+    }
+
+    fn move_kinematic(
+        _scene: &mut Self::Scene,
+        _colliders: &Query<Self::Colliders>,
+        _entity: Entity,
+        body: &mut BodyItem<'_, Self>,
+        translation: Vec3,
+        settings: &KinematicSettings,
+    ) -> KinematicMove {
+        let up = settings.up;
+        let half_extent = capsule_half_extent(body.3, body.4, crouched_height(body.5));
+        let (transform, _, physx_controller, _, _, _) = body;
+        let physx_controller = match physx_controller {
+            Some(physx_controller) => physx_controller,
+            None => return KinematicMove::default(),
+        };
+
+        // NOTE: No obstacles are used, so the elapsed time does not matter
+        physx_controller.set_up_direction(up);
+        // PhysX treats steeper slopes as walls and slides the controller down them
+        physx_controller.set_slope_limit(settings.max_slope_angle.cos());
+        // PhysX climbs steps itself
+        physx_controller.set_step_offset(settings.max_step_height);
+        let position = physx_controller.get_position();
+        let mut flags = physx_controller.move_(translation, KINEMATIC_MIN_DISTANCE, 0.0);
+        let mut step = 0.0;
+        if flags.contains(CollisionFlags::COLLISION_SIDES) {
+            let climbed = (physx_controller.get_position() - position - translation).dot(up);
+            if climbed > KINEMATIC_MIN_DISTANCE {
+                step = climbed;
+            }
+        }
+
+        // Step down: try to move down onto the ground below, and go back if
+        // there is none within the step height
+        if settings.snap_to_ground && !flags.contains(CollisionFlags::COLLISION_DOWN) {
+            let before_snap = physx_controller.get_position();
+            let snap_flags =
+                physx_controller.move_(-up * settings.max_step_height, KINEMATIC_MIN_DISTANCE, 0.0);
+            if snap_flags.contains(CollisionFlags::COLLISION_DOWN) {
+                step -= (before_snap - physx_controller.get_position()).dot(up);
+                flags |= snap_flags;
+            } else {
+                physx_controller.set_position(before_snap);
+            }
+        }
+
+        let new_position = physx_controller.get_position();
+        let moved = new_position - position;
+        transform.translation += moved;
+
+        // The PhysX controller only reports which sides were hit, so make up
+        // contacts from the collision flags
+        let mut hits = Vec::new();
+        if flags.contains(CollisionFlags::COLLISION_DOWN) {
+            hits.push(ShapeHit {
+                entity: None,
+                distance: 0.0,
+                point: new_position - half_extent * up,
+                normal: up,
+            });
+        }
+        if flags.contains(CollisionFlags::COLLISION_UP) {
+            hits.push(ShapeHit {
+                entity: None,
+                distance: 0.0,
+                point: new_position + half_extent * up,
+                normal: -up,
+            });
+        }
+        let blocked = horizontal(translation - moved, up);
+        if flags.contains(CollisionFlags::COLLISION_SIDES) && blocked.length_squared() > 1E-6 {
+            let normal = -blocked.normalize();
+            hits.push(ShapeHit {
+                entity: None,
+                distance: 0.0,
+                point: new_position - normal * half_extent,
+                normal,
+            });
+        }
+        KinematicMove {
+            translation: moved,
+            hits,
+            step,
+        }
+    }
+
+    fn translate_kinematic(
+        _scene: &mut Self::Scene,
+        body: &mut BodyItem<'_, Self>,
+        translation: Vec3,
+    ) {
+        let (transform, _, physx_controller, _, _, _) = body;
+        if let Some(physx_controller) = physx_controller {
+            let position = physx_controller.get_position() + translation;
+            physx_controller.set_position(position);
+            transform.translation += translation;
+        }
+    }
+
+    fn platform_velocity(
+        scene: &Self::Scene,
+        platform: &PlatformItem<'_, Self>,
+        point: Vec3,
+    ) -> (Vec3, Vec3) {
+        let (body_handle, transform) = platform;
+        let body = scene
+            .scene
+            .get_dynamic(body_handle.0)
+            .expect("Failed to get dynamic rigid body");
+        let linear = body.get_linear_velocity();
+        let angular = body.get_angular_velocity();
+        (
+            linear + angular.cross(point - transform.translation),
+            angular,
+        )
+    }
+
+    fn teleport(scene: &mut Self::Scene, body: &mut BodyItem<'_, Self>, translation: Vec3) {
+        match body.1 {
+            Some(body_handle) => {
+                let rigid_body = scene
+                    .scene
+                    .get_dynamic_mut(body_handle.0)
+                    .expect("Failed to get dynamic rigid body");
+                let position = rigid_body.get_global_position() + translation;
+                rigid_body.set_global_position(position, true);
+                body.0.translation += translation;
+            }
+            None => Self::translate_kinematic(scene, body, translation),
+        }
+    }
+
+    fn cast_shape(
+        scene: &Self::Scene,
+        colliders: &Query<Self::Colliders>,
+        entity: Entity,
+        body: &BodyItem<'_, Self>,
+        offset: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+    ) -> Option<ShapeHit> {
+        // NOTE: This approximates a sweep of the capsule with ray casts from
+        // points spread over the side of the capsule that faces the direction
+        // of travel, down to its bottom
+        let (transform, _, physx_controller, collider, capsule_controller, crouched) = body;
+        // A PhysX controller keeps its capsule along its up direction
+        let axis = match physx_controller {
+            Some(physx_controller) => physx_controller.get_up_direction(),
+            None => transform.rotation * Vec3::Y,
+        };
+        let radius = capsule_radius(*collider, *capsule_controller);
+        let half_height =
+            capsule_half_extent(*collider, *capsule_controller, crouched_height(*crouched))
+                - radius;
+        let filter = CastFilter {
+            ignore_entities: vec![entity],
+            ignore_groups: 0,
+        };
+        closest_hit(
+            capsule_front_points(
+                transform.translation + offset,
+                axis,
+                half_height,
+                radius,
+                direction,
+            )
+            .into_iter()
+            .filter_map(|point| {
+                raycast_past_ignored(
+                    scene,
+                    colliders,
+                    point + 1E-3 * direction,
+                    direction,
+                    max_distance,
+                    &filter,
+                )
+            }),
+        )
+    }
+
+    fn cast_sphere(
+        scene: &Self::Scene,
+        colliders: &Query<Self::Colliders>,
+        origin: Vec3,
+        direction: Vec3,
+        radius: f32,
+        max_distance: f32,
+        filter: &CastFilter,
+    ) -> Option<ShapeHit> {
+        // NOTE: This approximates a sweep of the sphere with ray casts along
+        // its centre and around its rim. Collision groups are not supported.
+        let (side, other_side) = direction.any_orthonormal_pair();
+        let rays = [
+            (Vec3::ZERO, radius),
+            (side * radius, 0.0),
+            (-side * radius, 0.0),
+            (other_side * radius, 0.0),
+            (-other_side * radius, 0.0),
+        ];
+        closest_hit(rays.iter().filter_map(|&(offset, reach)| {
+            raycast_past_ignored(
+                scene,
+                colliders,
+                origin + offset,
+                direction,
+                max_distance + reach,
+                filter,
+            )
+            .map(|hit| ShapeHit {
+                distance: (hit.distance - reach).max(0.0),
+                ..hit
+            })
+        }))
+    }
+
+    fn set_crouched(
+        _commands: &mut Commands,
+        _entity: Entity,
+        body: &mut BodyItem<'_, Self>,
+        crouched: bool,
+        offset: f32,
+    ) -> CrouchResize {
+        // NOTE: Dynamic bodies keep their full collider while crouching, only
+        // the head is lowered
+        let (transform, _, physx_controller, _, capsule_controller, _) = body;
+        match (physx_controller, capsule_controller) {
+            (Some(physx_controller), Some(desc)) => {
+                // NOTE: Resizing a PhysX controller keeps its bottom in place
+                // and moves its centre, which the body origin follows
+                let offset = offset.min(desc.height);
+                let height = if crouched {
+                    desc.height - offset
+                } else {
+                    desc.height
+                };
+                let position = physx_controller.get_position();
+                physx_controller.resize(height);
+                let moved = physx_controller.get_position() - position;
+                transform.translation += moved;
+                CrouchResize {
+                    height: offset,
+                    shift: moved.dot(physx_controller.get_up_direction()),
+                }
+            }
+            _ => CrouchResize {
+                height: offset,
+                shift: 0.0,
+            },
+        }
+    }
+}
+
+const KINEMATIC_MIN_DISTANCE: f32 = 1E-3;
+
+/// Distance a ray cast skips past a collider that it ignores
+const IGNORED_HIT_SKIP: f32 = 0.05;
+/// Most ignored colliders that a ray cast looks past
+const MAX_IGNORED_HITS: usize = 16;
+
+/// The first hit of a ray cast on a collider that `filter` does not ignore.
+/// PhysX reports only the closest hit, so the ray is cast again from past each
+/// ignored collider.
+fn raycast_past_ignored(
+    scene: &PhysX,
+    colliders: &Query<<PhysXBackend as CharacterPhysicsBackend>::Colliders>,
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    filter: &CastFilter,
+) -> Option<ShapeHit> {
+    let mut travelled = 0.0;
+    for _ in 0..MAX_IGNORED_HITS {
+        let hit = scene.scene.raycast(
+            origin + direction * travelled,
+            direction,
+            max_distance - travelled,
+        )?;
+        let entity = colliders
+            .iter()
+            .find(|(_, static_handle, dynamic_handle)| {
+                static_handle.map_or(false, |handle| handle.0 == hit.actor)
+                    || dynamic_handle.map_or(false, |handle| handle.0 == hit.actor)
+            })
+            .map(|(entity, _, _)| entity);
+        let distance = travelled + hit.distance;
+        if !filter.ignores(entity) {
+            return Some(ShapeHit {
+                entity,
+                distance,
+                point: hit.position,
+                normal: hit.normal,
+            });
+        }
+        travelled = distance + IGNORED_HIT_SKIP;
+        if travelled >= max_distance {
+            return None;
+        }
+    }
+    None
+}
+
+/// Height removed from the collider of a crouching body
+fn crouched_height(crouched: Option<&Crouched>) -> f32 {
+    crouched.map_or(0.0, |crouched| crouched.0)
+}
+
+/// Distance from the body origin to the bottom of its capsule. Only PhysX
+/// controllers are shrunk by `crouched`.
+fn capsule_half_extent(
+    collider: Option<&PhysXColliderDesc>,
+    capsule_controller: Option<&PhysXCapsuleControllerDesc>,
+    crouched: f32,
+) -> f32 {
+    match (collider, capsule_controller) {
+        (Some(PhysXColliderDesc::Capsule(radius, height)), _) => 0.5 * height + radius,
+        (_, Some(desc)) => 0.5 * (desc.height - crouched) + desc.radius,
+        _ => 0.0,
+    }
+}
+
+/// Radius of the body's capsule
+fn capsule_radius(
+    collider: Option<&PhysXColliderDesc>,
+    capsule_controller: Option<&PhysXCapsuleControllerDesc>,
+) -> f32 {
+    match (collider, capsule_controller) {
+        (Some(PhysXColliderDesc::Capsule(radius, _)), _) => *radius,
+        (_, Some(desc)) => desc.radius,
+        _ => 0.0,
+    }
+}
+
+/// How far the ray casts that approximate a capsule sweep tilt away from the
+/// direction of travel, as tangents of the tilt
+const CAPSULE_RAY_TILTS: [f32; 2] = [0.6, 2.0];
+
+/// Points on the surface of the capsule around `center` that face
+/// `direction`. Ray casts from them leave the capsule at once, so together
+/// they approximate a sweep of the capsule.
+fn capsule_front_points(
+    center: Vec3,
+    axis: Vec3,
+    half_height: f32,
+    radius: f32,
+    direction: Vec3,
+) -> Vec<Vec3> {
+    let (side, other_side) = direction.any_orthonormal_pair();
+    let mut normals = vec![direction];
+    for &tilt in CAPSULE_RAY_TILTS.iter() {
+        for &across in &[side, -side, other_side, -other_side] {
+            normals.push((direction + tilt * across).normalize());
+        }
+    }
+    let mut points = Vec::new();
+    for normal in normals {
+        let along = normal.dot(axis);
+        if along.abs() < 1E-3 {
+            // On the side of the cylinder, all the way along it
+            for &t in &[-1.0, 0.0, 1.0] {
+                points.push(center + t * half_height * axis + radius * normal);
+            }
+        } else {
+            // On the cap at the end that the normal points towards
+            points.push(center + along.signum() * half_height * axis + radius * normal);
+        }
+    }
+    points
+}
+
+/// The closest of `hits`, ignoring any with a distance that is not finite
+fn closest_hit(hits: impl Iterator<Item = ShapeHit>) -> Option<ShapeHit> {
+    hits.filter(|hit| hit.distance.is_finite())
+        .fold(None, |closest: Option<ShapeHit>, hit| match closest {
+            Some(closest) if closest.distance <= hit.distance => Some(closest),
+            _ => Some(hit),
+        })
+}
 
 pub struct PhysXKinematicTranslationCharacterControllerPlugin;
 
 impl Plugin for PhysXKinematicTranslationCharacterControllerPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        app.add_system(create_mass.system())
-            .add_system(constrain_rotation.system())
-            .add_system_to_stage(CoreStage::PreUpdate, body_to_velocity.system())
-            .add_system(controller_to_yaw.system())
-            .add_system(controller_to_pitch.system());
+        app.add_plugin(CharacterControllerPlugin::<PhysXBackend>::new(
+            ControllerMode::KinematicTranslation,
+        ))
+        .add_system(controller_to_yaw.system())
+        .add_system(controller_to_pitch.system());
     }
 }
 
@@ -18,34 +478,23 @@ pub struct PhysXDynamicImpulseCharacterControllerPlugin;
 
 impl Plugin for PhysXDynamicImpulseCharacterControllerPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        app.add_system(create_mass.system())
-            .add_system(constrain_rotation.system())
-            .add_system_to_stage(CoreStage::PreUpdate, body_to_velocity.system())
-            // IMPORTANT: The impulse/force systems MUST run before the physics simulation step, so they
-            // either need to be added to the end of PRE_UPDATE or the beginning of UPDATE
-            .add_system_to_stage(
-                CoreStage::PreUpdate,
-                controller_to_physx_dynamic_impulse.system(),
-            )
-            .add_system(controller_to_yaw.system())
-            .add_system(controller_to_pitch.system());
+        app.add_plugin(CharacterControllerPlugin::<PhysXBackend>::new(
+            ControllerMode::DynamicImpulse,
+        ))
+        .add_system(controller_to_yaw.system())
+        .add_system(controller_to_pitch.system());
     }
 }
+
 pub struct PhysXDynamicForceCharacterControllerPlugin;
 
 impl Plugin for PhysXDynamicForceCharacterControllerPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        app.add_system(create_mass.system())
-            .add_system(constrain_rotation.system())
-            .add_system_to_stage(CoreStage::PreUpdate, body_to_velocity.system())
-            // IMPORTANT: The impulse/force systems MUST run before the physics simulation step, so they
-            // either need to be added to the end of PRE_UPDATE or the beginning of UPDATE
-            .add_system_to_stage(
-                CoreStage::PreUpdate,
-                controller_to_physx_dynamic_force.system(),
-            )
-            .add_system(controller_to_yaw.system())
-            .add_system(controller_to_pitch.system());
+        app.add_plugin(CharacterControllerPlugin::<PhysXBackend>::new(
+            ControllerMode::DynamicForce,
+        ))
+        .add_system(controller_to_yaw.system())
+        .add_system(controller_to_pitch.system());
     }
 }
 
@@ -66,73 +515,22 @@ pub fn constrain_rotation(
     }
 }
 
-pub fn create_mass(
-    mut commands: Commands,
-    physx: Res<PhysX>,
-    query: Query<(Entity, &PhysXDynamicRigidBodyHandle), Without<Mass>>,
-) {
-    for (entity, body_handle) in query.iter() {
-        let body = physx
-            .scene
-            .get_dynamic(body_handle.0)
-            .expect("Failed to get dynamic rigid body");
-        commands.entity(entity).insert(Mass::new(body.get_mass()));
-    }
-}
-
-pub fn body_to_velocity(
-    physx: Res<PhysX>,
-    mut query: Query<(&PhysXDynamicRigidBodyHandle, &mut CharacterController), With<BodyTag>>,
-) {
-    for (body_handle, mut controller) in query.iter_mut() {
-        let body = physx
-            .scene
-            .get_dynamic(body_handle.0)
-            .expect("Failed to get dynamic rigid body");
-        controller.velocity = body.get_linear_velocity();
-    }
-}
-
-pub fn controller_to_physx_dynamic_impulse(
-    impulses: Res<Events<ImpulseEvent>>,
-    mut reader: ResMut<ControllerEvents>,
-    mut physx: ResMut<PhysX>,
-    query: Query<&PhysXDynamicRigidBodyHandle, With<BodyTag>>,
+/// Dynamic PhysX bodies can not be made to pass through other bodies, so
+/// dynamic characters that start noclipping fly with collisions instead
+pub fn reject_dynamic_noclip(
+    mut query: Query<
+        (Entity, &mut CharacterController),
+        (With<BodyTag>, With<PhysXDynamicRigidBodyHandle>),
+    >,
 ) {
-    let mut impulse = Vec3::ZERO;
-    for event in reader.impulses.iter(&impulses) {
-        impulse += **event;
-    }
-
-    if impulse.length_squared() > 1E-6 {
-        for body_handle in query.iter() {
-            let body = physx
-                .scene
-                .get_dynamic_mut(body_handle.0)
-                .expect("Failed to get dynamic rigid body");
-            body.add_force(impulse, physx::rigid_body::ForceMode::Impulse, true);
-        }
-    }
-}
-
-pub fn controller_to_physx_dynamic_force(
-    forces: Res<Events<ForceEvent>>,
-    mut reader: ResMut<ControllerEvents>,
-    mut physx: ResMut<PhysX>,
-    query: Query<&PhysXDynamicRigidBodyHandle, With<BodyTag>>,
-) {
-    let mut force = Vec3::ZERO;
-    for event in reader.forces.iter(&forces) {
-        force += **event;
-    }
-
-    if force.length_squared() > 1E-6 {
-        for body_handle in query.iter() {
-            let body = physx
-                .scene
-                .get_dynamic_mut(body_handle.0)
-                .expect("Failed to get dynamic rigid body");
-            body.add_force(force, physx::rigid_body::ForceMode::Force, true);
+    for (entity, mut controller) in query.iter_mut() {
+        if controller.noclip() {
+            warn!(
+                "{:?}: noclip is not supported for dynamic PhysX characters, flying with \
+                 collisions instead",
+                entity
+            );
+            controller.fly_mode = FlyMode::Collide;
         }
     }
 }
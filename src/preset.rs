@@ -0,0 +1,132 @@
+/*
+ * Controller presets
+ *
+ * Controller tuning, input bindings and look settings can be loaded from
+ * `.controller.ron` files. Characters with a Handle<ControllerPreset> take on
+ * the preset once it is loaded and again whenever it changes, so with asset
+ * watching enabled, editing the file retunes the characters live.
+ */
+
+use crate::{controller::CharacterController, look::LookSettings};
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    utils::{BoxedFuture, HashSet},
+};
+use serde::{Deserialize, Serialize};
+
+pub const APPLY_CONTROLLER_PRESETS_SYSTEM: &str = "apply_controller_presets";
+
+#[derive(Debug, Default, Deserialize, Serialize, TypeUuid)]
+#[uuid = "3b6e2f1c-7f0d-4c61-9a52-5d8e1b7a40c9"]
+#[serde(default)]
+pub struct ControllerPreset {
+    pub controller: CharacterController,
+    /// Look settings to apply too, if any
+    pub look: Option<LookSettings>,
+    /// Whether to apply the controller's input map too. This replaces any
+    /// bindings made at runtime, also whenever the preset is reloaded.
+    pub apply_input_map: bool,
+}
+
+#[derive(Default)]
+pub struct ControllerPresetLoader;
+
+impl AssetLoader for ControllerPresetLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let preset = ron::de::from_bytes::<ControllerPreset>(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(preset));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["controller.ron"]
+    }
+}
+
+pub struct ControllerPresetPlugin;
+
+impl Plugin for ControllerPresetPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_asset::<ControllerPreset>()
+            .init_asset_loader::<ControllerPresetLoader>()
+            .add_system(
+                apply_controller_presets
+                    .system()
+                    .label(APPLY_CONTROLLER_PRESETS_SYSTEM),
+            );
+    }
+}
+
+pub fn apply_controller_presets(
+    mut preset_events: EventReader<AssetEvent<ControllerPreset>>,
+    presets: Res<Assets<ControllerPreset>>,
+    mut query: QuerySet<(
+        Query<
+            (
+                &Handle<ControllerPreset>,
+                &mut CharacterController,
+                Option<&mut LookSettings>,
+            ),
+            Changed<Handle<ControllerPreset>>,
+        >,
+        Query<(
+            &Handle<ControllerPreset>,
+            &mut CharacterController,
+            Option<&mut LookSettings>,
+        )>,
+    )>,
+) {
+    // Characters that were just given a preset that has already been loaded
+    for (handle, mut controller, look_settings) in query.q0_mut().iter_mut() {
+        if let Some(preset) = presets.get(handle) {
+            apply_preset(preset, &mut controller, look_settings);
+        }
+    }
+
+    // Presets that have been loaded or edited since the last frame
+    let mut changed = HashSet::default();
+    for event in preset_events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                changed.insert(handle.clone());
+            }
+            AssetEvent::Removed { .. } => {}
+        }
+    }
+    if changed.is_empty() {
+        return;
+    }
+    for (handle, mut controller, look_settings) in query.q1_mut().iter_mut() {
+        if !changed.contains(handle) {
+            continue;
+        }
+        if let Some(preset) = presets.get(handle) {
+            apply_preset(preset, &mut controller, look_settings);
+        }
+    }
+}
+
+fn apply_preset(
+    preset: &ControllerPreset,
+    controller: &mut CharacterController,
+    look_settings: Option<Mut<LookSettings>>,
+) {
+    controller.set_tuning(&preset.controller);
+    if preset.apply_input_map {
+        controller.input_map = preset.controller.input_map.clone();
+    }
+    if let (Some(look), Some(mut look_settings)) = (preset.look, look_settings) {
+        // Keep the current orientation
+        let yaw_pitch_roll = look_settings.yaw_pitch_roll;
+        *look_settings = look;
+        look_settings.yaw_pitch_roll = yaw_pitch_roll;
+    }
+}
@@ -1,149 +1,307 @@
-use crate::{controller::*, events::*};
+use crate::{backend::*, controller::*};
 use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
 
-pub struct RapierDynamicImpulseCharacterControllerPlugin;
+pub const SYNC_GRAVITY_SYSTEM: &str = "sync_gravity";
+pub const CONTROLLER_TO_NOCLIP_SYSTEM: &str = "controller_to_noclip";
 
-pub const BODY_TO_VELOCITY_SYSTEM: &str = "body_to_velocity";
-pub const CONTROLLER_TO_RAPIER_DYNAMIC_IMPULSE_SYSTEM: &str =
-    "controller_to_rapier_dynamic_impulse";
-pub const CONTROLLER_TO_RAPIER_DYNAMIC_FORCE_SYSTEM: &str = "controller_to_rapier_dynamic_force";
-pub const CREATE_MASS_FROM_RAPIER_SYSTEM: &str = "create_mass_from_rapier";
-pub const TOGGLE_FLY_MODE_SYSTEM: &str = "toggle_fly_mode";
+pub struct RapierBackend;
 
-impl Plugin for RapierDynamicImpulseCharacterControllerPlugin {
-    fn build(&self, app: &mut AppBuilder) {
-        app.add_plugin(CharacterControllerPlugin)
-            .add_system_to_stage(
-                CoreStage::PreUpdate,
-                toggle_fly_mode
-                    .system()
-                    .label(TOGGLE_FLY_MODE_SYSTEM)
-                    .after(INPUT_TO_EVENTS_SYSTEM),
-            )
-            // NOTE: This must come after the bevy_rapier3d finalize_collider_attach_to_bodies system
-            .add_system(
-                create_mass_from_rapier
-                    .system()
-                    .label(CREATE_MASS_FROM_RAPIER_SYSTEM),
-            )
-            .add_system(body_to_velocity.system().label(BODY_TO_VELOCITY_SYSTEM))
-            .add_system(
-                controller_to_rapier_dynamic_impulse
-                    .system()
-                    .label(CONTROLLER_TO_RAPIER_DYNAMIC_IMPULSE_SYSTEM)
-                    .after(BODY_TO_VELOCITY_SYSTEM),
-            )
-            .add_system(controller_to_yaw.system())
-            .add_system(controller_to_pitch.system());
+impl CharacterPhysicsBackend for RapierBackend {
+    type Scene = QueryPipeline;
+    type Body = (
+        &'static mut RigidBodyVelocity,
+        &'static mut RigidBodyForces,
+        &'static mut RigidBodyActivation,
+        &'static mut RigidBodyPosition,
+        &'static RigidBodyMassProps,
+        &'static ColliderPosition,
+        &'static ColliderShape,
+        &'static ColliderFlags,
+    );
+    type Colliders = (
+        Entity,
+        &'static ColliderPosition,
+        &'static ColliderShape,
+        &'static ColliderFlags,
+    );
+    type Platform = (&'static RigidBodyVelocity, &'static RigidBodyMassProps);
+
+    fn build(app: &mut AppBuilder) {
+        app.add_system_to_stage(
+            CoreStage::PreUpdate,
+            sync_gravity.system().label(SYNC_GRAVITY_SYSTEM),
+        )
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            controller_to_noclip
+                .system()
+                .label(CONTROLLER_TO_NOCLIP_SYSTEM)
+                .after(INPUT_TO_EVENTS_SYSTEM),
+        );
     }
-}
 
-pub struct RapierDynamicForceCharacterControllerPlugin;
+    fn mass(_scene: &Self::Scene, body: &BodyItem<'_, Self>) -> Option<f32> {
+        let mass_props = body.4;
+        // Kinematic bodies have infinite mass and are not moved by impulses / forces
+        Some(if mass_props.effective_inv_mass > 0.0 {
+            1.0 / mass_props.effective_inv_mass
+        } else {
+            0.0
+        })
+    }
 
-impl Plugin for RapierDynamicForceCharacterControllerPlugin {
-    fn build(&self, app: &mut AppBuilder) {
-        app.add_plugin(CharacterControllerPlugin)
-            .add_system_to_stage(
-                CoreStage::PreUpdate,
-                toggle_fly_mode
-                    .system()
-                    .label(TOGGLE_FLY_MODE_SYSTEM)
-                    .after(INPUT_TO_EVENTS_SYSTEM),
-            )
-            // NOTE: This must come after the bevy_rapier3d finalize_collider_attach_to_bodies system
-            .add_system(
-                create_mass_from_rapier
-                    .system()
-                    .label(CREATE_MASS_FROM_RAPIER_SYSTEM),
+    fn velocity(_scene: &Self::Scene, body: &BodyItem<'_, Self>) -> Vec3 {
+        body.0.linvel.into()
+    }
+
+    fn apply_impulse(_scene: &mut Self::Scene, body: &mut BodyItem<'_, Self>, impulse: Vec3) {
+        body.0.apply_impulse(body.4, impulse.into());
+        body.2.wake_up(true);
+    }
+
+    fn apply_force(_scene: &mut Self::Scene, body: &mut BodyItem<'_, Self>, force: Vec3) {
+        let force: Vector<Real> = force.into();
+        body.1.force += force;
+        body.2.wake_up(true);
+    }
+
+    fn apply_gravity(
+        scene: &mut Self::Scene,
+        body: &mut BodyItem<'_, Self>,
+        gravity: Vec3,
+        mass: f32,
+    ) {
+        // Turn off the world gravity for this body and apply its own instead.
+        // Rapier clears the forces after each step.
+        body.1.gravity_scale = 0.0;
+        if gravity.length_squared() > 1E-6 {
+            Self::apply_force(scene, body, gravity * mass);
+        }
+    }
+
+    fn move_kinematic(
+        scene: &mut Self::Scene,
+        colliders: &Query<Self::Colliders>,
+        entity: Entity,
+        body: &mut BodyItem<'_, Self>,
+        translation: Vec3,
+        settings: &KinematicSettings,
+    ) -> KinematicMove {
+        let movement =
+            collide_slide_and_step::<Self>(scene, colliders, entity, body, translation, settings);
+        let start: Vec3 = body.3.position.translation.vector.into();
+        body.3.next_position.translation.vector = (start + movement.translation).into();
+        movement
+    }
+
+    fn translate_kinematic(
+        _scene: &mut Self::Scene,
+        body: &mut BodyItem<'_, Self>,
+        translation: Vec3,
+    ) {
+        let start: Vec3 = body.3.position.translation.vector.into();
+        body.3.next_position.translation.vector = (start + translation).into();
+    }
+
+    fn platform_velocity(
+        _scene: &Self::Scene,
+        platform: &PlatformItem<'_, Self>,
+        point: Vec3,
+    ) -> (Vec3, Vec3) {
+        let (velocity, mass_props) = platform;
+        let linear: Vec3 = velocity.linvel.into();
+        let angular: Vec3 = velocity.angvel.into();
+        let center: Vec3 = mass_props.world_com.coords.into();
+        (linear + angular.cross(point - center), angular)
+    }
+
+    fn teleport(_scene: &mut Self::Scene, body: &mut BodyItem<'_, Self>, translation: Vec3) {
+        // Move the current position too, so that dynamic bodies do not pick up
+        // the jump as velocity
+        let position: Vec3 = body.3.position.translation.vector.into();
+        body.3.position.translation.vector = (position + translation).into();
+        let next_position: Vec3 = body.3.next_position.translation.vector.into();
+        body.3.next_position.translation.vector = (next_position + translation).into();
+        body.2.wake_up(true);
+    }
+
+    fn cast_shape(
+        scene: &Self::Scene,
+        colliders: &Query<Self::Colliders>,
+        entity: Entity,
+        body: &BodyItem<'_, Self>,
+        offset: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+    ) -> Option<ShapeHit> {
+        let (_, _, _, _, _, collider_position, shape, flags) = body;
+        let colliders = QueryPipelineColliderComponentsSet(colliders);
+        let start: Vec3 = collider_position.0.translation.vector.into();
+        let mut shape_position = collider_position.0;
+        shape_position.translation.vector = (start + offset).into();
+        // Ignore the character's own collider
+        let filter = |handle: ColliderHandle| handle.entity() != entity;
+        scene
+            .cast_shape(
+                &colliders,
+                &shape_position,
+                &direction.into(),
+                &***shape,
+                max_distance,
+                flags.collision_groups,
+                Some(&filter),
             )
-            .add_system(body_to_velocity.system().label(BODY_TO_VELOCITY_SYSTEM))
-            .add_system(
-                controller_to_rapier_dynamic_force
-                    .system()
-                    .label(CONTROLLER_TO_RAPIER_DYNAMIC_FORCE_SYSTEM)
-                    .after(BODY_TO_VELOCITY_SYSTEM),
+            .map(|(handle, toi)| ShapeHit {
+                entity: Some(handle.entity()),
+                distance: toi.toi,
+                // The query pipeline is the first shape of the cast, so the first
+                // witness and normal are in world space on the hit collider
+                point: toi.witness1.coords.into(),
+                normal: (*toi.normal1).into(),
+            })
+    }
+
+    fn cast_sphere(
+        scene: &Self::Scene,
+        colliders: &Query<Self::Colliders>,
+        origin: Vec3,
+        direction: Vec3,
+        radius: f32,
+        max_distance: f32,
+        filter: &CastFilter,
+    ) -> Option<ShapeHit> {
+        let colliders = QueryPipelineColliderComponentsSet(colliders);
+        let position = Isometry::translation(origin.x, origin.y, origin.z);
+        let groups = InteractionGroups::new(u32::MAX, !filter.ignore_groups);
+        let ignore = |handle: ColliderHandle| !filter.ignores(Some(handle.entity()));
+        scene
+            .cast_shape(
+                &colliders,
+                &position,
+                &direction.into(),
+                &Ball::new(radius),
+                max_distance,
+                groups,
+                Some(&ignore),
             )
-            .add_system(controller_to_yaw.system())
-            .add_system(controller_to_pitch.system());
+            .map(|(handle, toi)| ShapeHit {
+                entity: Some(handle.entity()),
+                distance: toi.toi,
+                point: toi.witness1.coords.into(),
+                normal: (*toi.normal1).into(),
+            })
     }
-}
 
-pub fn create_mass_from_rapier(
-    mut commands: Commands,
-    query: Query<(Entity, &RigidBodyMassProps), Without<Mass>>,
-) {
-    for (entity, mass_props) in query.iter() {
-        let mass = 1.0 / mass_props.effective_inv_mass;
-        commands.entity(entity).insert(Mass::new(mass));
+    fn set_crouched(
+        commands: &mut Commands,
+        entity: Entity,
+        body: &mut BodyItem<'_, Self>,
+        crouched: bool,
+        offset: f32,
+    ) -> CrouchResize {
+        let capsule = match body.6.as_capsule() {
+            Some(capsule) => capsule,
+            None => {
+                return CrouchResize {
+                    height: offset,
+                    shift: 0.0,
+                }
+            }
+        };
+        // Keep the bottom of the capsule where it is and move the top, without
+        // shrinking the segment past the bottom
+        let offset = if crouched {
+            offset.min((capsule.segment.b - capsule.segment.a).norm())
+        } else {
+            offset
+        };
+        let change = if crouched { -offset } else { offset };
+        let (mut a, mut b) = (capsule.segment.a, capsule.segment.b);
+        if a.y > b.y {
+            a.y += change;
+        } else {
+            b.y += change;
+        }
+        commands
+            .entity(entity)
+            .insert(ColliderShape::capsule(a, b, capsule.radius));
+        CrouchResize {
+            height: offset,
+            shift: 0.0,
+        }
     }
 }
 
-pub fn body_to_velocity(
-    mut query: Query<(&RigidBodyVelocity, &mut CharacterController), With<BodyTag>>,
-) {
-    for (velocity, mut controller) in query.iter_mut() {
-        controller.velocity = velocity.linvel.into();
+pub struct RapierKinematicTranslationCharacterControllerPlugin;
+
+impl Plugin for RapierKinematicTranslationCharacterControllerPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_plugin(CharacterControllerPlugin::<RapierBackend>::new(
+            ControllerMode::KinematicTranslation,
+        ))
+        .add_system(controller_to_yaw.system())
+        .add_system(controller_to_pitch.system());
     }
 }
 
-pub fn controller_to_rapier_dynamic_impulse(
-    mut impulses: EventReader<ImpulseEvent>,
-    mut query: Query<
-        (
-            &mut RigidBodyVelocity,
-            &mut RigidBodyActivation,
-            &RigidBodyMassProps,
-        ),
-        With<BodyTag>,
-    >,
-) {
-    let mut impulse = Vec3::ZERO;
-    for event in impulses.iter() {
-        impulse += **event;
-    }
-    if impulse.length_squared() > 1E-6 {
-        for (mut velocity, mut activation, mass_props) in query.iter_mut() {
-            velocity.apply_impulse(mass_props, impulse.into());
-            activation.wake_up(true);
-        }
+pub struct RapierDynamicImpulseCharacterControllerPlugin;
+
+impl Plugin for RapierDynamicImpulseCharacterControllerPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_plugin(CharacterControllerPlugin::<RapierBackend>::new(
+            ControllerMode::DynamicImpulse,
+        ))
+        .add_system(controller_to_yaw.system())
+        .add_system(controller_to_pitch.system());
     }
 }
 
-pub fn controller_to_rapier_dynamic_force(
-    mut forces: EventReader<ForceEvent>,
-    mut query: Query<(&mut RigidBodyForces, &mut RigidBodyActivation), With<BodyTag>>,
-) {
-    let mut force = Vec3::ZERO;
-    for event in forces.iter() {
-        force += **event;
+pub struct RapierDynamicForceCharacterControllerPlugin;
+
+impl Plugin for RapierDynamicForceCharacterControllerPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_plugin(CharacterControllerPlugin::<RapierBackend>::new(
+            ControllerMode::DynamicForce,
+        ))
+        .add_system(controller_to_yaw.system())
+        .add_system(controller_to_pitch.system());
     }
+}
 
-    if force.length_squared() > 1E-6 {
-        for (mut forces, mut activation) in query.iter_mut() {
-            forces.force = force.into();
-            activation.wake_up(true);
-        }
+/// Keep the gravity of the physics world, which applies to everything other
+/// than the characters, in step with the `Gravity` resource
+fn sync_gravity(gravity: Res<Gravity>, mut rapier_config: ResMut<RapierConfiguration>) {
+    if gravity.is_changed() {
+        rapier_config.gravity = gravity.0.into();
     }
 }
 
-const NO_GRAVITY: [f32; 3] = [0.0, 0.0, 0.0];
-const GRAVITY: [f32; 3] = [0.0, -9.81, 0.0];
+/// The collision groups of a character from before it started noclipping
+pub struct NoclipCollisionGroups(pub InteractionGroups);
 
-fn toggle_fly_mode(
-    keyboard_input: Res<Input<KeyCode>>,
-    mut rapier_config: ResMut<RapierConfiguration>,
-    mut query: Query<(&CharacterController, &mut ColliderFlags)>,
+fn controller_to_noclip(
+    mut commands: Commands,
+    mut query: Query<(
+        Entity,
+        &CharacterController,
+        &mut ColliderFlags,
+        Option<&NoclipCollisionGroups>,
+    )>,
 ) {
-    for (controller, mut collider_flags) in query.iter_mut() {
-        if keyboard_input.just_pressed(controller.input_map.key_fly) {
-            rapier_config.gravity = if controller.fly {
+    for (entity, controller, mut collider_flags, saved_groups) in query.iter_mut() {
+        match (controller.noclip(), saved_groups) {
+            (true, None) => {
+                commands
+                    .entity(entity)
+                    .insert(NoclipCollisionGroups(collider_flags.collision_groups));
                 collider_flags.collision_groups = InteractionGroups::none();
-                NO_GRAVITY.into()
-            } else {
-                collider_flags.collision_groups = InteractionGroups::default();
-                GRAVITY.into()
-            };
+            }
+            (false, Some(saved_groups)) => {
+                collider_flags.collision_groups = saved_groups.0;
+                commands.entity(entity).remove::<NoclipCollisionGroups>();
+            }
+            _ => {}
         }
     }
 }
@@ -0,0 +1,253 @@
+/*
+ * Runtime rebinding
+ *
+ * Send a RebindRequest to listen for the next key, mouse button, mouse wheel
+ * or gamepad button press and bind it to an action of a character. Bindings
+ * that conflict with other actions are handled according to the
+ * RebindSettings. Every change is reported with a BindingChanged event and,
+ * if the character has a BindingsFile, saved to that file.
+ */
+
+use crate::{
+    controller::CharacterController,
+    events::{BindingChanged, BindingConflict},
+    gamepad::CharacterGamepad,
+    input_map::{Action, Binding},
+};
+use bevy::{input::mouse::MouseWheel, prelude::*};
+use std::path::PathBuf;
+
+pub const HANDLE_REBIND_REQUESTS_SYSTEM: &str = "handle_rebind_requests";
+pub const LISTEN_FOR_BINDING_SYSTEM: &str = "listen_for_binding";
+pub const SAVE_BINDINGS_SYSTEM: &str = "save_bindings";
+
+#[derive(Debug)]
+pub enum RebindRequest {
+    /// Bind the next input to `action`, replacing `replace` if given, or else
+    /// adding to the existing bindings. Only button-like input is captured,
+    /// so axis actions are better rebound through `InputMap` directly.
+    Listen {
+        entity: Entity,
+        action: Action,
+        replace: Option<Binding>,
+    },
+    /// Stop listening without changing any bindings
+    Cancel { entity: Entity },
+    /// Restore the default bindings of an action, or of all actions if `None`
+    Reset {
+        entity: Entity,
+        action: Option<Action>,
+    },
+}
+
+/// What to do when the captured input is already bound to another action
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    /// Bind it to both actions
+    Allow,
+    /// Remove it from the other actions, along with any chord or directional
+    /// binding that uses it
+    Steal,
+    /// Send a `BindingConflict` event and keep listening
+    Reject,
+}
+
+pub struct RebindSettings {
+    pub conflict_policy: ConflictPolicy,
+    /// Key that cancels listening instead of being bound
+    pub cancel_key: Option<KeyCode>,
+}
+
+impl Default for RebindSettings {
+    fn default() -> Self {
+        Self {
+            conflict_policy: ConflictPolicy::Reject,
+            cancel_key: Some(KeyCode::Escape),
+        }
+    }
+}
+
+/// Added to a character body while it is waiting for input to bind
+#[derive(Debug)]
+pub struct ListeningForBinding {
+    pub action: Action,
+    pub replace: Option<Binding>,
+}
+
+/// File that a character's input map is saved to whenever it is rebound
+#[derive(Debug)]
+pub struct BindingsFile(pub PathBuf);
+
+pub struct RebindPlugin;
+
+impl Plugin for RebindPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_event::<RebindRequest>()
+            .add_event::<BindingChanged>()
+            .add_event::<BindingConflict>()
+            .init_resource::<RebindSettings>()
+            .add_system(
+                handle_rebind_requests
+                    .system()
+                    .label(HANDLE_REBIND_REQUESTS_SYSTEM),
+            )
+            .add_system(
+                listen_for_binding
+                    .system()
+                    .label(LISTEN_FOR_BINDING_SYSTEM)
+                    .after(HANDLE_REBIND_REQUESTS_SYSTEM),
+            )
+            .add_system(
+                save_bindings
+                    .system()
+                    .label(SAVE_BINDINGS_SYSTEM)
+                    .after(LISTEN_FOR_BINDING_SYSTEM),
+            );
+    }
+}
+
+pub fn handle_rebind_requests(
+    mut commands: Commands,
+    mut requests: EventReader<RebindRequest>,
+    mut changed_events: EventWriter<BindingChanged>,
+    mut query: Query<&mut CharacterController>,
+) {
+    for request in requests.iter() {
+        match request {
+            RebindRequest::Listen {
+                entity,
+                action,
+                replace,
+            } => {
+                commands.entity(*entity).insert(ListeningForBinding {
+                    action: *action,
+                    replace: replace.clone(),
+                });
+            }
+            RebindRequest::Cancel { entity } => {
+                commands.entity(*entity).remove::<ListeningForBinding>();
+            }
+            RebindRequest::Reset { entity, action } => {
+                let mut controller = match query.get_mut(*entity) {
+                    Ok(controller) => controller,
+                    Err(_) => continue,
+                };
+                let actions = match action {
+                    Some(action) => vec![*action],
+                    None => Action::ALL.to_vec(),
+                };
+                for action in actions {
+                    controller.input_map.reset(action);
+                    changed_events.send(BindingChanged::new(
+                        *entity,
+                        action,
+                        controller.input_map.bindings(action),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn listen_for_binding(
+    mut commands: Commands,
+    settings: Res<RebindSettings>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_button_input: Res<Input<MouseButton>>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    mut mouse_wheel_events: EventReader<MouseWheel>,
+    mut changed_events: EventWriter<BindingChanged>,
+    mut conflict_events: EventWriter<BindingConflict>,
+    mut query: Query<(
+        Entity,
+        &ListeningForBinding,
+        &mut CharacterController,
+        Option<&CharacterGamepad>,
+    )>,
+) {
+    let mut mouse_wheel = 0.0;
+    for wheel in mouse_wheel_events.iter() {
+        mouse_wheel += wheel.y;
+    }
+
+    for (entity, listening, mut controller, gamepad) in query.iter_mut() {
+        if let Some(cancel_key) = settings.cancel_key {
+            if keyboard_input.just_pressed(cancel_key) {
+                commands.entity(entity).remove::<ListeningForBinding>();
+                continue;
+            }
+        }
+
+        let binding = if let Some(key) = keyboard_input.get_just_pressed().next() {
+            Binding::Key(*key)
+        } else if let Some(button) = mouse_button_input.get_just_pressed().next() {
+            Binding::MouseButton(*button)
+        } else if mouse_wheel > 0.0 {
+            Binding::MouseWheelUp
+        } else if mouse_wheel < 0.0 {
+            Binding::MouseWheelDown
+        } else if let Some(button) = gamepad.and_then(|gamepad| {
+            gamepad_buttons
+                .get_just_pressed()
+                .find(|button| button.0 == gamepad.gamepad)
+        }) {
+            Binding::GamepadButton(button.1)
+        } else {
+            continue;
+        };
+
+        let action = listening.action;
+        let conflicts = controller.input_map.conflicts(action, &binding);
+        if !conflicts.is_empty() {
+            match settings.conflict_policy {
+                ConflictPolicy::Allow => {}
+                ConflictPolicy::Steal => {
+                    for other in conflicts {
+                        controller.input_map.unbind_overlapping(other, &binding);
+                        changed_events.send(BindingChanged::new(
+                            entity,
+                            other,
+                            controller.input_map.bindings(other),
+                        ));
+                    }
+                }
+                ConflictPolicy::Reject => {
+                    conflict_events
+                        .send(BindingConflict::new(entity, action, &binding, &conflicts));
+                    continue;
+                }
+            }
+        }
+
+        if let Some(replace) = &listening.replace {
+            controller.input_map.unbind(action, replace);
+        }
+        controller.input_map.bind(action, binding);
+        changed_events.send(BindingChanged::new(
+            entity,
+            action,
+            controller.input_map.bindings(action),
+        ));
+        commands.entity(entity).remove::<ListeningForBinding>();
+    }
+}
+
+pub fn save_bindings(
+    mut changed_events: EventReader<BindingChanged>,
+    query: Query<(&CharacterController, &BindingsFile)>,
+) {
+    let mut saved = Vec::new();
+    for event in changed_events.iter() {
+        let entity = event.entity();
+        if saved.contains(&entity) {
+            continue;
+        }
+        if let Ok((controller, file)) = query.get(entity) {
+            if let Err(error) = controller.input_map.save(&file.0) {
+                warn!("Failed to save bindings to {:?}: {}", file.0, error);
+            }
+            saved.push(entity);
+        }
+    }
+}
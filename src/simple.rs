@@ -0,0 +1,582 @@
+/*
+ * Built-in collision backend
+ *
+ * Resolves a character capsule against static boxes, planes and heightfields
+ * so that simple games and tests get collision without a physics engine.
+ */
+
+use crate::backend::*;
+use bevy::prelude::*;
+
+pub struct SimpleBackend;
+
+/// The scene of the `SimpleBackend`. The colliders are plain components, so
+/// there is nothing to store.
+#[derive(Default)]
+pub struct SimplePhysics;
+
+/// Capsule around the character body along `up`, offset by `center` from the
+/// body origin in a frame where y is `up`. `half_height` excludes the
+/// hemispherical caps. `move_kinematic` keeps `up` along the character's up.
+#[derive(Clone, Copy, Debug)]
+pub struct CapsuleCollider {
+    pub radius: f32,
+    pub half_height: f32,
+    pub center: Vec3,
+    pub up: Vec3,
+}
+
+/// Static axis-aligned box centred on the entity's translation
+#[derive(Clone, Copy, Debug)]
+pub struct AabbCollider {
+    pub half_extents: Vec3,
+}
+
+/// Static infinite plane through the entity's translation
+#[derive(Clone, Copy, Debug)]
+pub struct PlaneCollider {
+    pub normal: Vec3,
+}
+
+/// Static grid of heights centred on the entity's translation. `heights` is
+/// stored row by row, with rows along z and columns along x, and the grid
+/// covers `size` in x and z.
+#[derive(Clone, Debug)]
+pub struct HeightfieldCollider {
+    pub heights: Vec<f32>,
+    pub rows: usize,
+    pub columns: usize,
+    pub size: Vec2,
+}
+
+impl HeightfieldCollider {
+    fn height(&self, row: usize, column: usize) -> f32 {
+        self.heights[row * self.columns + column]
+    }
+
+    /// Height and surface normal at a point relative to the grid centre, if it
+    /// lies over the grid
+    fn sample(&self, x: f32, z: f32) -> Option<(f32, Vec3)> {
+        if self.rows < 2 || self.columns < 2 {
+            return None;
+        }
+        let cell = Vec2::new(
+            self.size.x / (self.columns - 1) as f32,
+            self.size.y / (self.rows - 1) as f32,
+        );
+        let u = (x + 0.5 * self.size.x) / cell.x;
+        let v = (z + 0.5 * self.size.y) / cell.y;
+        if u < 0.0 || v < 0.0 || u > (self.columns - 1) as f32 || v > (self.rows - 1) as f32 {
+            return None;
+        }
+        let column = (u.floor() as usize).min(self.columns - 2);
+        let row = (v.floor() as usize).min(self.rows - 2);
+        let (fu, fv) = (u - column as f32, v - row as f32);
+
+        let h00 = self.height(row, column);
+        let h01 = self.height(row, column + 1);
+        let h10 = self.height(row + 1, column);
+        let h11 = self.height(row + 1, column + 1);
+        let near = h00 * (1.0 - fu) + h01 * fu;
+        let far = h10 * (1.0 - fu) + h11 * fu;
+        let height = near * (1.0 - fv) + far * fv;
+        let dh_dx = ((h01 - h00) * (1.0 - fv) + (h11 - h10) * fv) / cell.x;
+        let dh_dz = ((h10 - h00) * (1.0 - fu) + (h11 - h01) * fu) / cell.y;
+        Some((height, Vec3::new(-dh_dx, 1.0, -dh_dz).normalize()))
+    }
+}
+
+/// Closest surface to a point: signed distance (negative inside), outward
+/// normal and the point on the surface
+#[derive(Clone, Copy, Debug)]
+struct Contact {
+    distance: f32,
+    normal: Vec3,
+    point: Vec3,
+}
+
+fn aabb_contact(center: Vec3, half_extents: Vec3, point: Vec3) -> Contact {
+    let local = point - center;
+    let clamped = local.max(-half_extents).min(half_extents);
+    let outside = local - clamped;
+    let distance = outside.length();
+    if distance > 1E-6 {
+        return Contact {
+            distance,
+            normal: outside / distance,
+            point: center + clamped,
+        };
+    }
+    // Inside the box - push out through the nearest face
+    let depth = half_extents - local.abs();
+    let normal = if depth.x < depth.y && depth.x < depth.z {
+        Vec3::X * local.x.signum()
+    } else if depth.y < depth.z {
+        Vec3::Y * local.y.signum()
+    } else {
+        Vec3::Z * local.z.signum()
+    };
+    let penetration = depth.x.min(depth.y).min(depth.z);
+    Contact {
+        distance: -penetration,
+        normal,
+        point: point + normal * penetration,
+    }
+}
+
+fn plane_contact(origin: Vec3, normal: Vec3, point: Vec3) -> Contact {
+    let normal = normal.normalize();
+    let distance = (point - origin).dot(normal);
+    Contact {
+        distance,
+        normal,
+        point: point - normal * distance,
+    }
+}
+
+fn heightfield_contact(
+    origin: Vec3,
+    heightfield: &HeightfieldCollider,
+    point: Vec3,
+) -> Option<Contact> {
+    let local = point - origin;
+    heightfield
+        .sample(local.x, local.z)
+        .map(|(height, normal)| Contact {
+            distance: (local.y - height) * normal.y,
+            normal,
+            point: origin + Vec3::new(local.x, height, local.z),
+        })
+}
+
+/// Number of points along the capsule segment that are tested for contacts
+const CAPSULE_SAMPLES: usize = 5;
+
+/// The closest static collider facing against `direction` for the capsule at
+/// `position`, with the distance measured from the capsule surface. Surfaces
+/// the capsule is moving away from or along, and colliders that `filter`
+/// ignores, are skipped.
+fn closest_contact(
+    colliders: &Query<<SimpleBackend as CharacterPhysicsBackend>::Colliders>,
+    capsule: &CapsuleCollider,
+    position: Vec3,
+    direction: Vec3,
+    filter: &CastFilter,
+) -> Option<(Entity, Contact)> {
+    let center = position + Quat::from_rotation_arc(Vec3::Y, capsule.up) * capsule.center;
+    let mut closest: Option<(Entity, Contact)> = None;
+    for (entity, transform, aabb, plane, heightfield) in colliders.iter() {
+        if filter.ignores(Some(entity)) {
+            continue;
+        }
+        for i in 0..CAPSULE_SAMPLES {
+            let t = i as f32 / (CAPSULE_SAMPLES - 1) as f32;
+            let point = center + (2.0 * t - 1.0) * capsule.half_height * capsule.up;
+            let contact = if let Some(aabb) = aabb {
+                Some(aabb_contact(
+                    transform.translation,
+                    aabb.half_extents,
+                    point,
+                ))
+            } else if let Some(plane) = plane {
+                Some(plane_contact(transform.translation, plane.normal, point))
+            } else if let Some(heightfield) = heightfield {
+                heightfield_contact(transform.translation, heightfield, point)
+            } else {
+                None
+            };
+            let contact = contact.filter(|contact| contact.normal.dot(direction) < 0.0);
+            if let Some(mut contact) = contact {
+                contact.distance -= capsule.radius;
+                if closest.map_or(true, |(_, closest)| contact.distance < closest.distance) {
+                    closest = Some((entity, contact));
+                }
+            }
+        }
+    }
+    closest
+}
+
+/// A contact that the capsule is touching and moving into
+fn blocking_contact(
+    colliders: &Query<<SimpleBackend as CharacterPhysicsBackend>::Colliders>,
+    capsule: &CapsuleCollider,
+    position: Vec3,
+    direction: Vec3,
+    filter: &CastFilter,
+) -> Option<(Entity, Contact)> {
+    closest_contact(colliders, capsule, position, direction, filter)
+        .filter(|(_, contact)| contact.distance <= 0.0)
+}
+
+/// Sweep the capsule by stepping along `direction` and refining the first
+/// blocking contact found with a binary search, passing through colliders
+/// that `filter` ignores
+fn sweep(
+    colliders: &Query<<SimpleBackend as CharacterPhysicsBackend>::Colliders>,
+    capsule: &CapsuleCollider,
+    start: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    filter: &CastFilter,
+) -> Option<ShapeHit> {
+    let step = (0.5 * capsule.radius).max(1E-2);
+    let mut previous = 0.0;
+    let mut t = 0.0;
+    loop {
+        if let Some((entity, contact)) =
+            blocking_contact(colliders, capsule, start + direction * t, direction, filter)
+        {
+            let (mut lo, mut hi) = (previous, t);
+            let mut hit = (entity, contact);
+            for _ in 0..8 {
+                let mid = 0.5 * (lo + hi);
+                match blocking_contact(
+                    colliders,
+                    capsule,
+                    start + direction * mid,
+                    direction,
+                    filter,
+                ) {
+                    Some(found) => {
+                        hi = mid;
+                        hit = found;
+                    }
+                    None => lo = mid,
+                }
+            }
+            let (entity, contact) = hit;
+            return Some(ShapeHit {
+                entity: Some(entity),
+                distance: lo,
+                point: contact.point,
+                normal: contact.normal,
+            });
+        }
+        if t >= max_distance {
+            return None;
+        }
+        previous = t;
+        t = (t + step).min(max_distance);
+    }
+}
+
+impl CharacterPhysicsBackend for SimpleBackend {
+    type Scene = SimplePhysics;
+    type Body = (&'static mut Transform, &'static mut CapsuleCollider);
+    type Colliders = (
+        Entity,
+        &'static GlobalTransform,
+        Option<&'static AabbCollider>,
+        Option<&'static PlaneCollider>,
+        Option<&'static HeightfieldCollider>,
+    );
+    type Platform = ();
+
+    fn build(app: &mut AppBuilder) {
+        app.init_resource::<SimplePhysics>();
+    }
+
+    fn mass(_scene: &Self::Scene, _body: &BodyItem<'_, Self>) -> Option<f32> {
+        // Kinematic bodies have no mass, but characters without a Mass are not
+        // moved by input_to_events
+        Some(0.0)
+    }
+
+    fn velocity(_scene: &Self::Scene, _body: &BodyItem<'_, Self>) -> Vec3 {
+        Vec3::ZERO
+    }
+
+    fn apply_impulse(_scene: &mut Self::Scene, _body: &mut BodyItem<'_, Self>, _impulse: Vec3) {}
+
+    fn apply_force(_scene: &mut Self::Scene, _body: &mut BodyItem<'_, Self>, _force: Vec3) {}
+
+    fn move_kinematic(
+        scene: &mut Self::Scene,
+        colliders: &Query<Self::Colliders>,
+        entity: Entity,
+        body: &mut BodyItem<'_, Self>,
+        translation: Vec3,
+        settings: &KinematicSettings,
+    ) -> KinematicMove {
+        body.1.up = settings.up;
+        let movement =
+            collide_slide_and_step::<Self>(scene, colliders, entity, body, translation, settings);
+        body.0.translation += movement.translation;
+        movement
+    }
+
+    fn translate_kinematic(
+        _scene: &mut Self::Scene,
+        body: &mut BodyItem<'_, Self>,
+        translation: Vec3,
+    ) {
+        body.0.translation += translation;
+    }
+
+    fn cast_shape(
+        _scene: &Self::Scene,
+        colliders: &Query<Self::Colliders>,
+        _entity: Entity,
+        body: &BodyItem<'_, Self>,
+        offset: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+    ) -> Option<ShapeHit> {
+        let (transform, capsule) = body;
+        sweep(
+            colliders,
+            capsule,
+            transform.translation + offset,
+            direction,
+            max_distance,
+            &CastFilter::default(),
+        )
+    }
+
+    fn cast_sphere(
+        _scene: &Self::Scene,
+        colliders: &Query<Self::Colliders>,
+        origin: Vec3,
+        direction: Vec3,
+        radius: f32,
+        max_distance: f32,
+        filter: &CastFilter,
+    ) -> Option<ShapeHit> {
+        // A capsule without a segment is a sphere
+        let sphere = CapsuleCollider {
+            radius,
+            half_height: 0.0,
+            center: Vec3::ZERO,
+            up: Vec3::Y,
+        };
+        sweep(colliders, &sphere, origin, direction, max_distance, filter)
+    }
+
+    fn set_crouched(
+        _commands: &mut Commands,
+        _entity: Entity,
+        body: &mut BodyItem<'_, Self>,
+        crouched: bool,
+        offset: f32,
+    ) -> CrouchResize {
+        // Keep the bottom of the capsule where it is and move the top, without
+        // shrinking the segment past the bottom
+        let capsule = &mut body.1;
+        let offset = if crouched {
+            offset.min(2.0 * capsule.half_height)
+        } else {
+            offset
+        };
+        let change = if crouched {
+            -0.5 * offset
+        } else {
+            0.5 * offset
+        };
+        capsule.half_height += change;
+        capsule.center.y += change;
+        CrouchResize {
+            height: offset,
+            shift: 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::schedule::{Stage, SystemStage};
+    use std::f32::consts::FRAC_PI_4;
+
+    const CAPSULE: CapsuleCollider = CapsuleCollider {
+        radius: 0.5,
+        half_height: 0.5,
+        center: Vec3::ZERO,
+        up: Vec3::Y,
+    };
+
+    fn spawn_box(world: &mut World, center: Vec3, half_extents: Vec3) -> Entity {
+        world
+            .spawn()
+            .insert_bundle((
+                GlobalTransform::from_translation(center),
+                AabbCollider { half_extents },
+            ))
+            .id()
+    }
+
+    fn sweep_in(
+        world: &mut World,
+        start: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+        filter: CastFilter,
+    ) -> Option<ShapeHit> {
+        world.insert_resource(None::<ShapeHit>);
+        let mut stage = SystemStage::single(
+            (move |colliders: Query<<SimpleBackend as CharacterPhysicsBackend>::Colliders>,
+                   mut hit: ResMut<Option<ShapeHit>>| {
+                *hit = sweep(
+                    &colliders,
+                    &CAPSULE,
+                    start,
+                    direction,
+                    max_distance,
+                    &filter,
+                );
+            })
+            .system(),
+        );
+        stage.run(world);
+        world.remove_resource::<Option<ShapeHit>>().unwrap()
+    }
+
+    fn move_in(world: &mut World, translation: Vec3, max_step_height: f32) -> KinematicMove {
+        let body = world
+            .spawn()
+            .insert_bundle((Transform::from_translation(Vec3::Y), CAPSULE))
+            .id();
+        let settings = KinematicSettings {
+            up: Vec3::Y,
+            max_slope_angle: FRAC_PI_4,
+            max_step_height,
+            snap_to_ground: true,
+        };
+        world.insert_resource(SimplePhysics);
+        world.insert_resource(KinematicMove::default());
+        let mut stage = SystemStage::single(
+            (move |mut scene: ResMut<SimplePhysics>,
+                   colliders: Query<<SimpleBackend as CharacterPhysicsBackend>::Colliders>,
+                   mut bodies: Query<<SimpleBackend as CharacterPhysicsBackend>::Body>,
+                   mut movement: ResMut<KinematicMove>| {
+                let mut item = bodies.get_mut(body).unwrap();
+                *movement = SimpleBackend::move_kinematic(
+                    &mut scene,
+                    &colliders,
+                    body,
+                    &mut item,
+                    translation,
+                    &settings,
+                );
+            })
+            .system(),
+        );
+        stage.run(world);
+        world.remove_resource::<KinematicMove>().unwrap()
+    }
+
+    #[test]
+    fn aabb_contact_outside() {
+        let contact = aabb_contact(Vec3::ZERO, Vec3::ONE, Vec3::new(3.0, 0.5, 0.0));
+        assert!((contact.distance - 2.0).abs() < 1E-6);
+        assert!((contact.normal - Vec3::X).length() < 1E-6);
+        assert!((contact.point - Vec3::new(1.0, 0.5, 0.0)).length() < 1E-6);
+    }
+
+    #[test]
+    fn aabb_contact_inside() {
+        let contact = aabb_contact(
+            Vec3::ZERO,
+            Vec3::new(2.0, 1.0, 2.0),
+            Vec3::new(0.5, 0.75, 0.0),
+        );
+        assert!((contact.distance + 0.25).abs() < 1E-6);
+        assert!((contact.normal - Vec3::Y).length() < 1E-6);
+        assert!((contact.point - Vec3::new(0.5, 1.0, 0.0)).length() < 1E-6);
+    }
+
+    #[test]
+    fn heightfield_sample() {
+        // Rising by 1 per unit along x
+        let heightfield = HeightfieldCollider {
+            heights: vec![0.0, 2.0, 0.0, 2.0],
+            rows: 2,
+            columns: 2,
+            size: Vec2::new(2.0, 2.0),
+        };
+        let (height, normal) = heightfield.sample(0.5, 0.25).unwrap();
+        assert!((height - 1.5).abs() < 1E-6);
+        assert!((normal - Vec3::new(-1.0, 1.0, 0.0).normalize()).length() < 1E-6);
+        assert!(heightfield.sample(1.5, 0.0).is_none());
+        assert!(heightfield.sample(0.0, -1.5).is_none());
+    }
+
+    #[test]
+    fn sweep_down_onto_box() {
+        let mut world = World::default();
+        spawn_box(&mut world, -0.5 * Vec3::Y, Vec3::new(10.0, 0.5, 10.0));
+        // The bottom of the capsule starts 1 above the top of the box
+        let hit = sweep_in(
+            &mut world,
+            2.0 * Vec3::Y,
+            -Vec3::Y,
+            5.0,
+            CastFilter::default(),
+        )
+        .unwrap();
+        assert!((hit.distance - 1.0).abs() < 1E-2);
+        assert!((hit.normal - Vec3::Y).length() < 1E-6);
+        assert!(sweep_in(
+            &mut world,
+            2.0 * Vec3::Y,
+            -Vec3::Y,
+            0.5,
+            CastFilter::default()
+        )
+        .is_none());
+        assert!(sweep_in(
+            &mut world,
+            2.0 * Vec3::Y,
+            Vec3::Y,
+            5.0,
+            CastFilter::default()
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn sweep_passes_through_ignored_colliders() {
+        let mut world = World::default();
+        let near = spawn_box(&mut world, 2.0 * Vec3::X, Vec3::splat(0.5));
+        let far = spawn_box(&mut world, 5.0 * Vec3::X, Vec3::splat(0.5));
+        let filter = CastFilter {
+            ignore_entities: vec![near],
+            ignore_groups: 0,
+        };
+        let hit = sweep_in(&mut world, Vec3::ZERO, Vec3::X, 10.0, filter).unwrap();
+        assert_eq!(hit.entity, Some(far));
+        assert!((hit.distance - 4.0).abs() < 1E-2);
+    }
+
+    #[test]
+    fn slide_along_wall() {
+        let mut world = World::default();
+        spawn_box(
+            &mut world,
+            Vec3::new(2.0, 1.0, 0.0),
+            Vec3::new(0.5, 5.0, 5.0),
+        );
+        let movement = move_in(&mut world, Vec3::new(2.0, 0.0, 2.0), 0.0);
+        assert!(movement.translation.x > 0.9 && movement.translation.x < 1.0);
+        assert!(movement.translation.z > 1.9);
+        assert!(movement.translation.y.abs() < 1E-6);
+        assert!(movement
+            .hits
+            .iter()
+            .any(|hit| (hit.normal + Vec3::X).length() < 1E-6));
+    }
+
+    #[test]
+    fn step_up_onto_ledge() {
+        let mut world = World::default();
+        spawn_box(
+            &mut world,
+            Vec3::new(2.0, 0.1, 0.0),
+            Vec3::new(0.5, 0.1, 5.0),
+        );
+        let movement = move_in(&mut world, Vec3::new(2.0, 0.0, 0.0), 0.3);
+        assert!((movement.translation.x - 2.0).abs() < 1E-3);
+        assert!((movement.step - 0.21).abs() < 2E-2);
+        assert!((movement.translation.y - movement.step).abs() < 1E-6);
+    }
+}